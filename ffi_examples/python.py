@@ -1,7 +1,7 @@
 import os
 import json
 import platform
-from ctypes import cdll
+from ctypes import c_void_p, cdll, string_at
 
 
 print(platform.uname())
@@ -29,6 +29,28 @@ lib.set_project_key("_foo_".encode("utf-8"))
 lib.set_project_slug("_slug_".encode("utf-8"))
 lib.set_instance_id("_instanceid_".encode("utf-8"))
 lib.set_ci_tracking_enabled(1)
+lib.set_ci_tracking_providers("github_actions,!jenkins".encode("utf-8"))
+lib.set_retry_policy(5, 30, 3600)
+lib.set_gzip(1)
+lib.set_log_limits(10 * 1024 * 1024, 10000)
+lib.set_event_store("file".encode("utf-8"))
+lib.set_metadata_limits(64 * 1024, 16 * 1024, 100, 10)
+lib.add_redaction_pattern(r"acme_[0-9a-f]{32}".encode("utf-8"))
+lib.set_context("os,os_version,arch,cls_version,terminal,stdout_tty,locale".encode("utf-8"))
+lib.set_api_timeout(5)
+lib.set_proxy("".encode("utf-8"))
+lib.set_ca_bundle("".encode("utf-8"))
+lib.set_transport("http".encode("utf-8"))
+lib.register_event_schema(
+    "_slug_".encode("utf-8"),
+    "command".encode("utf-8"),
+    json.dumps({"type": "object", "properties": {"version": {"type": "string"}}}).encode("utf-8"),
+)
+lib.register_event_schema(
+    "_slug_".encode("utf-8"),
+    "error".encode("utf-8"),
+    json.dumps({"type": "object", "required": ["stacktrace"]}).encode("utf-8"),
+)
 lib.track_event(
     "_slug_".encode("utf-8"),
     "command".encode("utf-8"),
@@ -37,6 +59,12 @@ lib.track_event(
 )
 lib.dispatch_events()
 lib.set_request_permission_prompt("_prompt_".encode("utf-8"))
+lib.set_consent_policy(2, "We now collect your OS and terminal type.".encode("utf-8"))
+lib.set_event_category("timing".encode("utf-8"), "performance".encode("utf-8"))
+lib.set_consent_categories("usage,performance".encode("utf-8"))
+lib.set_prompt_mode("together".encode("utf-8"))
+lib.set_privacy_policy_url("https://example.com/privacy".encode("utf-8"))
+lib.set_opt_out_command("example telemetry off".encode("utf-8"))
 lib.set_user_id("_user_id_".encode("utf-8"))
 lib.set_invocation_id("_invocation_id_".encode("utf-8"))
 lib.set_is_ci(
@@ -49,3 +77,16 @@ lib.track_event(
     json.dumps({"version": "1.0", "stacktrace":"A\nLong\nThing\n"}).encode("utf-8"),
     0,
 )
+lib.track_event(
+    "_slug_".encode("utf-8"),
+    "command".encode("utf-8"),
+    json.dumps({"version": "1.0"}).encode("utf-8"),
+    1,
+)
+lib.flush_events(5000)
+
+# What a "telemetry status" command would show
+lib.get_tracking_status.restype = c_void_p
+status = lib.get_tracking_status()
+print(json.loads(string_at(status)))
+lib.free_string(c_void_p(status))
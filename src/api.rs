@@ -1,19 +1,176 @@
 use crate::events::Event;
+use chrono::{DateTime, UTC};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use reqwest;
 use reqwest::header::{self, HeaderMap, HeaderValue};
 use reqwest::Error as ReqwestError;
+use reqwest::StatusCode;
 use serde_json;
 use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::time::Duration;
 
 const DEFAULT_API_URL: &str = "https://api.cls.dev/";
 
+// Limits for a single batch request, so a large offline backlog
+// is split into a handful of reasonably sized POSTs
+const MAX_BATCH_EVENTS: usize = 100;
+const MAX_BATCH_BYTES: usize = 512 * 1024;
+
+// Smaller bodies aren't worth the CPU time to compress
+const GZIP_MIN_BYTES: usize = 1024;
+
+// Everything needed to build an APIClient, so it can be
+// captured from Settings and rebuilt only when it changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientOptions {
+    pub token: String,
+    pub gzip: bool,
+    pub timeout: Duration,
+    pub proxy: Option<String>,
+    pub ca_bundle: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    // Never got a response (DNS, refused connection, TLS, etc.)
+    Connection(ReqwestError),
+    Timeout,
+    // 5xx responses
+    Server(StatusCode),
+    // 429, with the number of seconds the server asked us to wait
+    RateLimited(Option<u64>),
+    // 401/403, the project key is wrong and nothing will get through until it's fixed
+    Unauthorized(StatusCode),
+    // 400/422, the server won't accept these events no matter how often we try
+    Rejected(StatusCode, String),
+    // Any other 4xx (404, 405, 407...), the request isn't reaching the events API,
+    // usually a wrong API URL or a proxy that wants credentials
+    Misconfigured(StatusCode),
+    // 413, the body was bigger than the server accepts
+    TooLarge,
+    // The client couldn't be built from the settings (bad proxy URL, unreadable CA bundle)
+    Config(String),
+    // Writing to a file or stream transport failed
+    Io(io::Error),
+}
+
+impl ApiError {
+    // Whether sending the same events again later could succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Connection(_)
+            | ApiError::Timeout
+            | ApiError::Server(_)
+            | ApiError::RateLimited(_)
+            | ApiError::TooLarge
+            | ApiError::Io(_) => true,
+            ApiError::Unauthorized(_)
+            | ApiError::Rejected(_, _)
+            | ApiError::Misconfigured(_)
+            | ApiError::Config(_) => false,
+        }
+    }
+
+    // Whether to stop sending anything else for now, rather than keep
+    // hitting an API that is refusing us
+    pub fn should_pause(&self) -> bool {
+        matches!(
+            self,
+            ApiError::RateLimited(_) | ApiError::Unauthorized(_) | ApiError::Misconfigured(_)
+        )
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ApiError::RateLimited(retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Connection(err) => write!(f, "connection error: {}", err),
+            ApiError::Timeout => write!(f, "request timed out"),
+            ApiError::Server(status) => write!(f, "server error: {}", status),
+            ApiError::RateLimited(Some(seconds)) => {
+                write!(f, "rate limited, retry after {}s", seconds)
+            }
+            ApiError::RateLimited(None) => write!(f, "rate limited"),
+            ApiError::Unauthorized(status) => write!(f, "unauthorized: {}", status),
+            ApiError::Rejected(status, body) => write!(f, "rejected: {} {}", status, body),
+            ApiError::Misconfigured(status) => {
+                write!(
+                    f,
+                    "not the events API, check the API URL and proxy: {}",
+                    status
+                )
+            }
+            ApiError::TooLarge => write!(f, "request body too large"),
+            ApiError::Config(message) => write!(f, "client configuration error: {}", message),
+            ApiError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl Error for ApiError {}
+
+impl From<ReqwestError> for ApiError {
+    fn from(err: ReqwestError) -> ApiError {
+        if err.is_timeout() {
+            ApiError::Timeout
+        } else {
+            ApiError::Connection(err)
+        }
+    }
+}
+
+// Retry-After can be either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(seconds);
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.timestamp() - UTC::now().timestamp()).max(0) as u64)
+}
+
+// A bundle can hold several PEM certificates, but reqwest only parses one at a time
+fn load_ca_bundle(path: &str) -> Result<Vec<reqwest::Certificate>, ApiError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| ApiError::Config(format!("can't read CA bundle {}: {}", path, err)))?;
+    let end_marker = "-----END CERTIFICATE-----";
+
+    let certificates = contents
+        .split_inclusive(end_marker)
+        .filter(|pem| pem.contains(end_marker))
+        .map(|pem| {
+            reqwest::Certificate::from_pem(pem.trim().as_bytes()).map_err(|err| {
+                ApiError::Config(format!("invalid certificate in {}: {}", path, err))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if certificates.is_empty() {
+        return Err(ApiError::Config(format!("no certificates in {}", path)));
+    }
+    Ok(certificates)
+}
+
 pub struct APIClient {
     base_url: String,
     client: reqwest::blocking::Client,
+    gzip: bool,
 }
 
 impl APIClient {
-    pub fn new(token: &str) -> APIClient {
+    pub fn new(options: &ClientOptions) -> Result<APIClient, ApiError> {
         let mut base_url = match env::var("CLS_API_URL") {
             Ok(url) => url,
             Err(_) => DEFAULT_API_URL.to_string(),
@@ -24,7 +181,8 @@ impl APIClient {
             base_url += "/";
         }
 
-        let mut auth_value = HeaderValue::from_str(format!("Token {}", token).as_str()).unwrap();
+        let mut auth_value =
+            HeaderValue::from_str(format!("Token {}", options.token).as_str()).unwrap();
         let mut headers = HeaderMap::new();
 
         auth_value.set_sensitive(true);
@@ -43,17 +201,33 @@ impl APIClient {
         );
 
         // get a client builder
-        let client = reqwest::blocking::Client::builder()
+        let mut builder = reqwest::blocking::Client::builder()
             .default_headers(headers)
+            .timeout(options.timeout);
+
+        if let Some(proxy) = &options.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|err| ApiError::Config(format!("invalid proxy {}: {}", proxy, err)))?;
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(ca_bundle) = &options.ca_bundle {
+            for certificate in load_ca_bundle(ca_bundle)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        let client = builder
             .build()
-            .unwrap();
+            .map_err(|err| ApiError::Config(err.to_string()))?;
 
-        APIClient {
+        Ok(APIClient {
             base_url: base_url,
             client: client,
-        }
+            gzip: options.gzip,
+        })
     }
-    fn post(&self, path: &str, json: &serde_json::Value) -> Result<(), ReqwestError> {
+    fn post(&self, path: &str, json: &serde_json::Value) -> Result<(), ApiError> {
         let mut url = self.base_url.to_string() + path.strip_prefix("/").unwrap_or(path);
 
         // Make sure it ends with a slash
@@ -61,18 +235,254 @@ impl APIClient {
             url += "/";
         }
 
-        let res = self.client.post(url).json(&json).send()?;
+        let body = serde_json::to_vec(json).unwrap();
+        let res = if self.gzip && body.len() >= GZIP_MIN_BYTES {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body).unwrap();
+            self.client
+                .post(url)
+                .header(header::CONTENT_ENCODING, "gzip")
+                .body(encoder.finish().unwrap())
+                .send()?
+        } else {
+            self.client.post(url).body(body).send()?
+        };
 
-        super::debug_print(format!(
-            "api_post {} {}",
-            res.status(),
-            res.text().unwrap_or("<no text>".to_string())
-        ));
+        let status = res.status();
+        let retry_after = res
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        let text = res.text().unwrap_or("<no text>".to_string());
+
+        super::debug_print(format!("api_post {} {}", status, text));
+
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(status_error(status, retry_after, text))
+        }
+    }
+    // Sends the events as JSON arrays, one chunk at a time as the iterator is consumed,
+    // so the caller knows exactly which events made it and can stop early
+    pub fn post_events<'a>(
+        &'a self,
+        events: &'a [Event],
+    ) -> impl Iterator<Item = (&'a [Event], Result<(), ApiError>)> + 'a {
+        let post = move |chunk: &[Event]| {
+            let json = serde_json::to_value(chunk).unwrap();
+            self.post("events/", &json)
+        };
+        chunk_events(events, MAX_BATCH_EVENTS, MAX_BATCH_BYTES)
+            .into_iter()
+            .flat_map(move |chunk| post_split(chunk, &post))
+    }
+}
+
+fn status_error(status: StatusCode, retry_after: Option<u64>, text: String) -> ApiError {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        ApiError::RateLimited(retry_after)
+    } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        ApiError::Unauthorized(status)
+    } else if status == StatusCode::REQUEST_TIMEOUT {
+        ApiError::Timeout
+    } else if status == StatusCode::PAYLOAD_TOO_LARGE {
+        ApiError::TooLarge
+    } else if status == StatusCode::BAD_REQUEST || status == StatusCode::UNPROCESSABLE_ENTITY {
+        ApiError::Rejected(status, text)
+    } else if status.is_client_error() {
+        ApiError::Misconfigured(status)
+    } else {
+        ApiError::Server(status)
+    }
+}
+
+type PostResults<'a> = Vec<(&'a [Event], Result<(), ApiError>)>;
+
+// Posts a chunk, splitting it in half and posting each half again when the
+// server turns the whole thing away (a 400 from one bad event, or a 413),
+// so only the events it rejects on their own fail
+fn post_split<'a>(
+    events: &'a [Event],
+    post: &impl Fn(&[Event]) -> Result<(), ApiError>,
+) -> PostResults<'a> {
+    let result = post(events);
+    split_failed(events, result, post)
+}
+
+fn split_failed<'a>(
+    events: &'a [Event],
+    result: Result<(), ApiError>,
+    post: &impl Fn(&[Event]) -> Result<(), ApiError>,
+) -> PostResults<'a> {
+    let split = is_bad_request(&result) || matches!(result, Err(ApiError::TooLarge));
+    if !split || events.len() < 2 {
+        return vec![(events, result)];
+    }
+
+    let (first, second) = events.split_at(events.len() / 2);
+    let first_result = post(first);
+    let second_result = post(second);
+    // Both halves rejected is the server refusing every event, not one bad one,
+    // so stop there instead of posting each of them on its own
+    if is_bad_request(&first_result) && is_bad_request(&second_result) {
+        return vec![(first, first_result), (second, second_result)];
+    }
+
+    let mut results = split_failed(first, first_result, post);
+    results.extend(split_failed(second, second_result, post));
+    results
+}
+
+fn is_bad_request(result: &Result<(), ApiError>) -> bool {
+    matches!(result, Err(ApiError::Rejected(status, _)) if *status == StatusCode::BAD_REQUEST)
+}
+
+// Splits events into consecutive chunks bounded by count and serialized size.
+// An event that is larger than max_bytes on its own still gets a chunk to itself.
+fn chunk_events(events: &[Event], max_events: usize, max_bytes: usize) -> Vec<&[Event]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+
+    for (i, event) in events.iter().enumerate() {
+        // +1 for the comma (or bracket) separating array items
+        let event_size = serde_json::to_vec(event).unwrap().len() + 1;
+        let count = i - start;
+        if count > 0 && (count >= max_events || size + event_size > max_bytes) {
+            chunks.push(&events[start..i]);
+            start = i;
+            size = 0;
+        }
+        size += event_size;
+    }
+    if start < events.len() {
+        chunks.push(&events[start..]);
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_event;
 
-        Ok(())
+    fn test_events(n: usize) -> Vec<Event> {
+        (0..n).map(|_| test_event("test")).collect()
     }
-    pub fn post_event(&self, event: &Event) -> Result<(), ReqwestError> {
-        let json = serde_json::to_value(event).unwrap();
-        self.post("events/", &json)
+
+    #[test]
+    fn chunk_by_count() {
+        let events = test_events(250);
+        let chunks = chunk_events(&events, 100, MAX_BATCH_BYTES);
+        let sizes: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![100, 100, 50]);
+    }
+
+    #[test]
+    fn chunk_by_bytes() {
+        let events = test_events(10);
+        let event_size = serde_json::to_vec(&events[0]).unwrap().len() + 1;
+        let chunks = chunk_events(&events, 100, event_size * 3);
+        let sizes: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![3, 3, 3, 1]);
+
+        // Oversized events are still sent, one per chunk
+        let chunks = chunk_events(&events, 100, 1);
+        assert_eq!(chunks.len(), 10);
+    }
+
+    #[test]
+    fn split_rejected_chunks() {
+        let mut events = test_events(8);
+        events[5].slug = "bad".to_string();
+        let post = |chunk: &[Event]| {
+            if chunk.len() > 4 {
+                Err(ApiError::TooLarge)
+            } else if chunk.iter().any(|event| event.slug == "bad") {
+                Err(ApiError::Rejected(StatusCode::BAD_REQUEST, "".to_string()))
+            } else {
+                Ok(())
+            }
+        };
+
+        let results = post_split(&events, &post);
+        let sizes: Vec<usize> = results.iter().map(|(chunk, _)| chunk.len()).collect();
+        assert_eq!(sizes, vec![4, 1, 1, 2]);
+        let failed: Vec<&str> = results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .flat_map(|(chunk, _)| chunk.iter().map(|event| event.slug.as_str()))
+            .collect();
+        assert_eq!(failed, vec!["bad"]);
+
+        // The server rejecting the whole body isn't split all the way down
+        let posts = std::cell::Cell::new(0);
+        let results = post_split(&events, &|_: &[Event]| {
+            posts.set(posts.get() + 1);
+            Err(ApiError::Rejected(StatusCode::BAD_REQUEST, "".to_string()))
+        });
+        let sizes: Vec<usize> = results.iter().map(|(chunk, _)| chunk.len()).collect();
+        assert_eq!(sizes, vec![4, 4]);
+        assert_eq!(posts.get(), 3);
+
+        // Anything else isn't the server rejecting part of the chunk
+        let results = post_split(&events, &|_: &[Event]| {
+            Err(ApiError::Server(StatusCode::BAD_GATEWAY))
+        });
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn chunk_empty() {
+        assert!(chunk_events(&[], 100, MAX_BATCH_BYTES).is_empty());
+    }
+
+    #[test]
+    fn should_pause() {
+        assert!(ApiError::RateLimited(None).should_pause());
+        assert!(ApiError::Unauthorized(StatusCode::FORBIDDEN).should_pause());
+        assert!(!ApiError::Timeout.should_pause());
+        assert!(!ApiError::Server(StatusCode::BAD_GATEWAY).should_pause());
+        assert!(!ApiError::TooLarge.should_pause());
+        assert!(ApiError::Misconfigured(StatusCode::NOT_FOUND).should_pause());
+    }
+
+    #[test]
+    fn status_errors() {
+        let error = |status| status_error(status, None, "".to_string());
+        assert!(matches!(
+            error(StatusCode::BAD_REQUEST),
+            ApiError::Rejected(_, _)
+        ));
+        assert!(matches!(
+            error(StatusCode::UNPROCESSABLE_ENTITY),
+            ApiError::Rejected(_, _)
+        ));
+        for status in &[
+            StatusCode::NOT_FOUND,
+            StatusCode::METHOD_NOT_ALLOWED,
+            StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+        ] {
+            assert!(matches!(error(*status), ApiError::Misconfigured(_)));
+        }
+        assert!(matches!(
+            error(StatusCode::PAYLOAD_TOO_LARGE),
+            ApiError::TooLarge
+        ));
+        assert!(matches!(
+            error(StatusCode::BAD_GATEWAY),
+            ApiError::Server(_)
+        ));
+    }
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(120));
+        assert_eq!(parse_retry_after(" 5 "), Some(5));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(0));
+        assert_eq!(parse_retry_after("soon"), None);
     }
 }
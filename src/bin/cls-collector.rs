@@ -0,0 +1,232 @@
+// A local stand-in for the cls API, to point CLS_API_URL at during development and tests.
+//
+//   cargo run --features collector --bin cls-collector -- \
+//       --port 8000 --output events.ndjson --fail-rate 0.5 --delay-ms 200
+//
+// Accepts the same POST events/ requests as the real API (single events or batches,
+// optionally gzipped), validates them against the Event model, and appends what it
+// receives to an NDJSON file. Events with an id it has already stored are skipped,
+// the same way the API deduplicates retries. Failures and delays can be injected to test how
+// dispatch behaves when the API is slow or unhealthy.
+use cls_ffi::Event;
+use flate2::read::GzDecoder;
+use rand::Rng;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+const USAGE: &str = "Usage: cls-collector [options]
+
+Options:
+    --port <port>            Port to listen on (default 8000)
+    --output <path>          NDJSON file to append events to (default events.ndjson)
+    --token <token>          Reject requests without this project key with a 401
+    --fail-rate <0.0-1.0>    Fraction of requests to fail (default 0)
+    --fail-status <status>   Status code for failed requests (default 500)
+    --retry-after <seconds>  Retry-After header to send with failed requests
+    --delay-ms <ms>          Wait before responding to every request (default 0)";
+
+struct Options {
+    port: u16,
+    output: String,
+    token: Option<String>,
+    fail_rate: f64,
+    fail_status: u16,
+    retry_after: Option<u64>,
+    delay: Duration,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        port: 8000,
+        output: "events.ndjson".to_string(),
+        token: None,
+        fail_rate: 0.0,
+        fail_status: 500,
+        retry_after: None,
+        delay: Duration::from_millis(0),
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", arg))?;
+        let invalid = || format!("Invalid value for {}: {}", arg, value);
+
+        match arg.as_str() {
+            "--port" => options.port = value.parse().map_err(|_| invalid())?,
+            "--output" => options.output = value.clone(),
+            "--token" => options.token = Some(value.clone()),
+            "--fail-rate" => options.fail_rate = value.parse().map_err(|_| invalid())?,
+            "--fail-status" => options.fail_status = value.parse().map_err(|_| invalid())?,
+            "--retry-after" => options.retry_after = Some(value.parse().map_err(|_| invalid())?),
+            "--delay-ms" => {
+                options.delay = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+            }
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+fn read_body(request: &mut Request) -> Result<Vec<u8>, String> {
+    let gzipped = header(request, "Content-Encoding") == Some("gzip");
+
+    let mut body = Vec::new();
+    let reader = request.as_reader();
+    let read = if gzipped {
+        GzDecoder::new(reader).read_to_end(&mut body)
+    } else {
+        reader.read_to_end(&mut body)
+    };
+    read.map_err(|err| format!("Unable to read body: {}", err))?;
+
+    Ok(body)
+}
+
+// Validates the body against the Event model, the same way the API accepts
+// either a single event object or a batch as an array
+fn parse_events(body: &[u8]) -> Result<Vec<Event>, String> {
+    let json: serde_json::Value =
+        serde_json::from_slice(body).map_err(|err| format!("Invalid JSON: {}", err))?;
+    let items = match json {
+        serde_json::Value::Array(items) => items,
+        item => vec![item],
+    };
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(i, item)| {
+            serde_json::from_value(item).map_err(|err| format!("Invalid event {}: {}", i, err))
+        })
+        .collect()
+}
+
+fn json_response(status: u16, json: serde_json::Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(json.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn handle(
+    request: &mut Request,
+    options: &Options,
+    seen: &mut HashSet<String>,
+) -> Response<std::io::Cursor<Vec<u8>>> {
+    if request.method() != &Method::Post || request.url().trim_end_matches('/') != "/events" {
+        return json_response(404, serde_json::json!({"error": "Not found"}));
+    }
+
+    if let Some(token) = &options.token {
+        if header(request, "Authorization") != Some(format!("Token {}", token).as_str()) {
+            return json_response(401, serde_json::json!({"error": "Invalid project key"}));
+        }
+    }
+
+    if options.fail_rate > 0.0 && rand::thread_rng().gen_bool(options.fail_rate.min(1.0)) {
+        let mut response = json_response(
+            options.fail_status,
+            serde_json::json!({"error": "Injected failure"}),
+        );
+        if let Some(seconds) = options.retry_after {
+            let header = Header::from_bytes("Retry-After", seconds.to_string()).unwrap();
+            response = response.with_header(header);
+        }
+        return response;
+    }
+
+    let events = match read_body(request).and_then(|body| parse_events(&body)) {
+        Ok(events) => events,
+        Err(err) => return json_response(400, serde_json::json!({ "error": err })),
+    };
+
+    // A retry of a request whose response was lost
+    let total = events.len();
+    let events: Vec<Event> = events
+        .into_iter()
+        .filter(|event| !seen.contains(&event.id))
+        .collect();
+
+    let mut lines = String::new();
+    for event in &events {
+        lines += &serde_json::to_string(event).unwrap();
+        lines += "\n";
+    }
+    let written = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&options.output)
+        .and_then(|mut file| file.write_all(lines.as_bytes()));
+    if let Err(err) = written {
+        eprintln!("Unable to write to {}: {}", options.output, err);
+        return json_response(500, serde_json::json!({"error": "Unable to store events"}));
+    }
+
+    seen.extend(events.iter().map(|event| event.id.clone()));
+    json_response(
+        201,
+        serde_json::json!({
+            "accepted": events.len(),
+            "duplicates": total - events.len(),
+        }),
+    )
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let address = format!("127.0.0.1:{}", options.port);
+    let server = match Server::http(&address) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("Unable to listen on {}: {}", address, err);
+            process::exit(1);
+        }
+    };
+    println!(
+        "Collecting events at http://{}/ into {}",
+        address, options.output
+    );
+
+    let mut seen = HashSet::new();
+    for mut request in server.incoming_requests() {
+        thread::sleep(options.delay);
+
+        let response = handle(&mut request, &options, &mut seen);
+        println!(
+            "{} {} {}",
+            request.method(),
+            request.url(),
+            response.status_code().0
+        );
+        if let Err(err) = request.respond(response) {
+            eprintln!("Unable to respond: {}", err);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::env;
+
+// An environment variable a provider sets on its builds, with
+// the value it's set to when that matters
+type Condition = (&'static str, Option<&'static str>);
+
+// CI providers and the variables that all have to be set for a build to be
+// on them. Checked in order, a provider can have more than one row. Some of
+// these are also set outside of builds (on a Jenkins agent's shell, or a
+// Vercel/Netlify deployment) so they're paired with a build-only variable.
+const PROVIDERS: &[(&str, &[Condition])] = &[
+    ("github_actions", &[("GITHUB_ACTIONS", Some("true"))]),
+    ("gitlab", &[("GITLAB_CI", None)]),
+    ("circleci", &[("CIRCLECI", None)]),
+    ("jenkins", &[("JENKINS_URL", None), ("BUILD_ID", None)]),
+    ("jenkins", &[("JENKINS_URL", None), ("JENKINS_HOME", None)]),
+    ("buildkite", &[("BUILDKITE", None)]),
+    ("azure_pipelines", &[("TF_BUILD", Some("true"))]),
+    ("travis", &[("TRAVIS", None)]),
+    ("bitbucket", &[("BITBUCKET_BUILD_NUMBER", None)]),
+    ("teamcity", &[("TEAMCITY_VERSION", None)]),
+    ("appveyor", &[("APPVEYOR", None)]),
+    ("aws_codebuild", &[("CODEBUILD_BUILD_ID", None)]),
+    ("drone", &[("DRONE", None)]),
+    ("semaphore", &[("SEMAPHORE", None)]),
+    ("bitrise", &[("BITRISE_IO", None)]),
+    ("netlify", &[("NETLIFY", Some("true")), ("BUILD_ID", None)]),
+    ("vercel", &[("VERCEL", Some("1")), ("CI", None)]),
+];
+
+// Which provider the current process is running on, if any
+pub fn detect_provider() -> Option<String> {
+    detect_provider_from(|name| env::var(name).ok())
+}
+
+fn detect_provider_from(get_env: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let matches = |(name, expected): &Condition| match (get_env(name), expected) {
+        (Some(val), Some(expected)) => val.eq_ignore_ascii_case(expected),
+        (Some(val), None) => !val.is_empty(),
+        (None, _) => false,
+    };
+    PROVIDERS
+        .iter()
+        .find(|(_, conditions)| conditions.iter().all(matches))
+        .map(|(provider, _)| provider.to_string())
+}
+
+// Per-provider overrides of ci_tracking_enabled, parsed from a comma separated
+// list like "github_actions,!jenkins". Listed providers are tracked, the ones
+// with a "!" aren't, and any others follow ci_tracking_enabled.
+pub fn parse_provider_rules(spec: &str) -> Result<Vec<(String, bool)>, String> {
+    let mut rules = Vec::new();
+    for rule in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+    {
+        let (provider, allowed) = match rule.strip_prefix('!') {
+            Some(provider) => (provider.trim(), false),
+            None => (rule, true),
+        };
+        if !PROVIDERS.iter().any(|(name, _)| *name == provider) {
+            return Err(format!("unknown CI provider {:?}", provider));
+        }
+        rules.push((provider.to_string(), allowed));
+    }
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn detect(vars: &[(&str, &str)]) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, val)| (name.to_string(), val.to_string()))
+            .collect();
+        detect_provider_from(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn detect_providers() {
+        assert_eq!(detect(&[]), None);
+        assert_eq!(detect(&[("CI", "true")]), None);
+        assert_eq!(
+            detect(&[("CI", "true"), ("GITHUB_ACTIONS", "true")]).as_deref(),
+            Some("github_actions")
+        );
+        assert_eq!(detect(&[("GITHUB_ACTIONS", "false")]), None);
+        assert_eq!(
+            detect(&[
+                ("JENKINS_URL", "https://jenkins.example.com/"),
+                ("BUILD_ID", "42")
+            ])
+            .as_deref(),
+            Some("jenkins")
+        );
+        assert_eq!(
+            detect(&[
+                ("JENKINS_URL", "https://jenkins.example.com/"),
+                ("JENKINS_HOME", "/var/jenkins_home")
+            ])
+            .as_deref(),
+            Some("jenkins")
+        );
+        assert_eq!(
+            detect(&[("NETLIFY", "true"), ("BUILD_ID", "abc")]).as_deref(),
+            Some("netlify")
+        );
+        assert_eq!(
+            detect(&[("VERCEL", "1"), ("CI", "1")]).as_deref(),
+            Some("vercel")
+        );
+        assert_eq!(
+            detect(&[("TF_BUILD", "True")]).as_deref(),
+            Some("azure_pipelines")
+        );
+    }
+
+    #[test]
+    fn not_a_build() {
+        // A developer's shell with the Jenkins URL exported for its CLI
+        assert_eq!(
+            detect(&[("JENKINS_URL", "https://jenkins.example.com/")]),
+            None
+        );
+        // Deployed functions, rather than their builds
+        assert_eq!(detect(&[("VERCEL", "1")]), None);
+        assert_eq!(detect(&[("NETLIFY", "true")]), None);
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert_eq!(
+            parse_provider_rules("github_actions, !jenkins").unwrap(),
+            vec![
+                ("github_actions".to_string(), true),
+                ("jenkins".to_string(), false)
+            ]
+        );
+        assert!(parse_provider_rules("").unwrap().is_empty());
+        assert!(parse_provider_rules("github").is_err());
+    }
+}
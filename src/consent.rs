@@ -0,0 +1,104 @@
+// What kind of data an event is, each with its own decision in settings.json
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Usage,
+    Errors,
+    Performance,
+}
+
+pub const CATEGORIES: &[Category] = &[Category::Usage, Category::Errors, Category::Performance];
+
+impl Category {
+    pub fn parse(name: &str) -> Result<Category, String> {
+        match name.trim() {
+            "usage" => Ok(Category::Usage),
+            "errors" => Ok(Category::Errors),
+            "performance" => Ok(Category::Performance),
+            name => Err(format!("unknown consent category {:?}", name)),
+        }
+    }
+
+    // Key in the settings.json "consent" object
+    pub fn key(&self) -> &'static str {
+        match self {
+            Category::Usage => "usage",
+            Category::Errors => "errors",
+            Category::Performance => "performance",
+        }
+    }
+
+    // Asked when the prompt mode is "separate"
+    pub fn question(&self) -> &'static str {
+        match self {
+            Category::Usage => {
+                "Can we collect anonymous usage data (which commands and features you use)?"
+            }
+            Category::Errors => "Can we collect anonymous error reports when something goes wrong?",
+            Category::Performance => {
+                "Can we collect anonymous performance data (how long commands take)?"
+            }
+        }
+    }
+
+    // Where event types go unless the project maps them with set_event_category
+    pub fn default_for_type(type_s: &str) -> Category {
+        match type_s {
+            "error" => Category::Errors,
+            "performance" => Category::Performance,
+            _ => Category::Usage,
+        }
+    }
+}
+
+// Parses a comma separated list of categories, like "usage,performance"
+pub fn parse_categories(spec: &str) -> Result<Vec<Category>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(Category::parse)
+        .collect()
+}
+
+// Whether the first prompt asks about every category at once, or one at a time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PromptMode {
+    Together,
+    Separate,
+}
+
+impl PromptMode {
+    pub fn parse(spec: &str) -> Result<PromptMode, String> {
+        match spec.trim() {
+            "" | "together" => Ok(PromptMode::Together),
+            "separate" => Ok(PromptMode::Separate),
+            spec => Err(format!("unknown prompt mode {:?}", spec)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_categories("usage, performance").unwrap(),
+            vec![Category::Usage, Category::Performance]
+        );
+        assert!(parse_categories("").unwrap().is_empty());
+        assert!(parse_categories("usage,crashes").is_err());
+        assert_eq!(PromptMode::parse("").unwrap(), PromptMode::Together);
+        assert!(PromptMode::parse("one-by-one").is_err());
+    }
+
+    #[test]
+    fn default_categories() {
+        assert_eq!(Category::default_for_type("command"), Category::Usage);
+        assert_eq!(Category::default_for_type("error"), Category::Errors);
+        assert_eq!(
+            Category::default_for_type("performance"),
+            Category::Performance
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use atty::Stream;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+// Every item that can be collected, in the order they're listed
+pub const CONTEXT_ITEMS: &[&str] = &[
+    "os",
+    "os_version",
+    "arch",
+    "cls_version",
+    "terminal",
+    "stdout_tty",
+    "locale",
+];
+
+// Looked up once per process, it can mean running a command like sw_vers
+static OS_INFO: Lazy<os_info::Info> = Lazy::new(os_info::get);
+
+// Details about the machine and environment an event came from,
+// collected by the library so host CLIs don't each have to
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cls_version: Option<String>,
+    // $TERM, like "xterm-256color"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terminal: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_tty: Option<bool>,
+    // Like "en_US.UTF-8"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+}
+
+impl Context {
+    // Collects the given items, None if there aren't any
+    pub fn collect(items: &[String]) -> Option<Context> {
+        if items.is_empty() {
+            return None;
+        }
+
+        let enabled = |item: &str| items.iter().any(|enabled| enabled == item);
+        let os_info = if enabled("os") || enabled("os_version") {
+            Some(&*OS_INFO)
+        } else {
+            None
+        };
+
+        Some(Context {
+            os: os_info
+                .filter(|_| enabled("os"))
+                .map(|info| info.os_type().to_string()),
+            os_version: os_info
+                .filter(|_| enabled("os_version"))
+                .map(|info| info.version().to_string()),
+            arch: Some(env::consts::ARCH.to_string()).filter(|_| enabled("arch")),
+            cls_version: Some(env!("CARGO_PKG_VERSION").to_string())
+                .filter(|_| enabled("cls_version")),
+            terminal: non_empty_env(&["TERM"]).filter(|_| enabled("terminal")),
+            stdout_tty: Some(atty::is(Stream::Stdout)).filter(|_| enabled("stdout_tty")),
+            locale: non_empty_env(&["LC_ALL", "LC_MESSAGES", "LANG"]).filter(|_| enabled("locale")),
+        })
+    }
+}
+
+// Parses a comma separated list of items, like "os,arch,locale".
+// "all" is every item, and an empty list turns the context off.
+pub fn parse_items(spec: &str) -> Result<Vec<String>, String> {
+    let mut items = Vec::new();
+    for item in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        if item == "all" {
+            items.extend(CONTEXT_ITEMS.iter().map(|item| item.to_string()));
+        } else if CONTEXT_ITEMS.contains(&item) {
+            items.push(item.to_string());
+        } else {
+            return Err(format!("unknown context item {:?}", item));
+        }
+    }
+    Ok(items)
+}
+
+// The first of the variables that's set, the way locale variables are looked up
+fn non_empty_env(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|val| !val.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_items("all").unwrap().len(), CONTEXT_ITEMS.len());
+        assert_eq!(parse_items(" os, arch ").unwrap(), vec!["os", "arch"]);
+        assert!(parse_items("").unwrap().is_empty());
+        assert!(parse_items("os,hostname").is_err());
+    }
+
+    #[test]
+    fn collect() {
+        assert_eq!(Context::collect(&[]), None);
+
+        let context = Context::collect(&parse_items("arch,cls_version").unwrap()).unwrap();
+        assert_eq!(context.arch.as_deref(), Some(env::consts::ARCH));
+        assert_eq!(
+            context.cls_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(context.os, None);
+        assert_eq!(context.stdout_tty, None);
+        assert_eq!(
+            serde_json::to_value(&context).unwrap(),
+            serde_json::json!({
+                "arch": env::consts::ARCH,
+                "cls_version": env!("CARGO_PKG_VERSION"),
+            })
+        );
+    }
+}
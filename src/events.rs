@@ -1,13 +1,23 @@
+use crate::context::Context;
+use crate::store::{Claim, EventStore};
+use crate::truncate::{self, MetadataLimits};
 use chrono::UTC;
+use fs2::FileExt;
+use serde::de::{self, DeserializeOwned, Deserializer};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::fs;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path;
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Event {
     // Maps directly to the API fields
+    // Unique to each event, so the API can ignore a retried copy of one it already
+    // has. Lines logged before ids existed get a new one when they're read.
+    #[serde(default = "new_event_id")]
+    pub id: String,
     pub slug: String,
     #[serde(alias = "type", rename(serialize = "type"))]
     pub type_s: String,
@@ -16,12 +26,26 @@ pub struct Event {
     pub invocation_id: String,
     #[serde(default)]
     pub ci: bool,
+    // Like "github_actions", if it was detected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ci_provider: Option<String>,
     pub datetime: String,
     #[serde(default)]
     pub version: String,
+    // Filled in by the library, the items to include are set with set_context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<Context>,
+    // Paths of the metadata values cut down to the MetadataLimits
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub truncated: Vec<String>,
+}
+
+fn new_event_id() -> String {
+    Uuid::new_v4().to_string()
 }
 
 impl Event {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         slug: &str,
         type_s: &str,
@@ -30,72 +54,389 @@ impl Event {
         invocation_id: &str,
         ci: &bool,
         version: &str,
+        metadata_limits: &MetadataLimits,
     ) -> Event {
+        let (metadata, truncated) = truncate::truncate(metadata, metadata_limits);
         Event {
+            id: new_event_id(),
             slug: slug.to_string(),
             type_s: type_s.to_string(),
             user_id: user_id.to_string(),
             invocation_id: invocation_id.to_string(),
             ci: ci.clone(),
+            ci_provider: None,
             metadata: metadata,
             datetime: format!("{}", UTC::now()),
             version: version.to_string(),
+            context: None,
+            truncated,
         }
     }
 }
 
+// An event as it's kept in an EventStore, along with the retry bookkeeping
+// that isn't sent to the API. Saved as one flat object, so lines logged
+// before there was any bookkeeping still parse.
+#[derive(Serialize, Debug, Clone)]
+pub struct StoredEvent {
+    #[serde(flatten)]
+    pub event: Event,
+    // How many times sending this event has failed
+    #[serde(skip_serializing_if = "is_zero")]
+    pub attempts: u32,
+    // Unix timestamp before which a failed event shouldn't be retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<i64>,
+}
+
+impl StoredEvent {
+    pub fn new(event: Event) -> StoredEvent {
+        StoredEvent {
+            event,
+            attempts: 0,
+            next_attempt_at: None,
+        }
+    }
+}
+
+// Read by hand, since #[serde(flatten)] ignores the aliases
+// on Event that let older lines ("type_s") still parse
+impl<'de> Deserialize<'de> for StoredEvent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<StoredEvent, D::Error> {
+        let mut fields = serde_json::Map::deserialize(deserializer)?;
+        let attempts: Option<u32> = take_field(&mut fields, "attempts")?;
+        let next_attempt_at: Option<i64> = take_field(&mut fields, "next_attempt_at")?;
+        let event =
+            serde_json::from_value(serde_json::Value::Object(fields)).map_err(de::Error::custom)?;
+        Ok(StoredEvent {
+            event,
+            attempts: attempts.unwrap_or(0),
+            next_attempt_at,
+        })
+    }
+}
+
+fn take_field<T: DeserializeOwned, E: de::Error>(
+    fields: &mut serde_json::Map<String, serde_json::Value>,
+    name: &str,
+) -> Result<Option<T>, E> {
+    match fields.remove(name) {
+        Some(value) => serde_json::from_value(value).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+pub const DEFAULT_MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_MAX_LOG_EVENTS: usize = 10_000;
+
+// No serialized event is smaller than this, so a log under
+// max_events * MIN_EVENT_BYTES can't have too many events
+const MIN_EVENT_BYTES: u64 = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLimits {
+    pub max_bytes: u64,
+    pub max_events: usize,
+}
+
+#[derive(Debug, Clone)]
 pub struct EventLog {
     path: path::PathBuf,
+    limits: LogLimits,
 }
 
 impl EventLog {
-    pub fn new(dir: &path::PathBuf) -> EventLog {
+    pub fn new(dir: &path::Path, limits: &LogLimits) -> EventLog {
         EventLog {
             path: dir.join("events.log"),
+            limits: limits.clone(),
         }
     }
 
-    pub fn record_event(&self, event: &Event) {
-        super::debug_print(format!("appending_event_log path={:?}", self.path));
+    pub fn dead_letter(dir: &path::Path, limits: &LogLimits) -> EventLog {
+        EventLog {
+            path: dir.join("dead_letter.log"),
+            limits: limits.clone(),
+        }
+    }
+
+    // Advisory lock shared by every process using this log,
+    // released when the returned file is dropped
+    fn lock(&self) -> fs::File {
+        fs::create_dir_all(self.path.parent().unwrap()).unwrap();
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("lock"))
+            .unwrap();
+        file.lock_exclusive().unwrap();
+        file
+    }
+
+    // Events being sent by dispatch_events are moved here, so appends
+    // from other processes can carry on while the requests are in flight
+    fn dispatching_path(&self) -> path::PathBuf {
+        self.path.with_extension("dispatching")
+    }
+
+    // Must be called with the lock held
+    fn enforce_limits(&self) {
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return,
+        };
+        if size <= self.limits.max_bytes && size <= self.limits.max_events as u64 * MIN_EVENT_BYTES
+        {
+            return;
+        }
+
+        let lines = read_lines(&self.path);
+        let mut bytes: u64 = lines.iter().map(|line| line.len() as u64 + 1).sum();
+        let mut dropped = 0;
+        while dropped < lines.len()
+            && (lines.len() - dropped > self.limits.max_events || bytes > self.limits.max_bytes)
+        {
+            bytes -= lines[dropped].len() as u64 + 1;
+            dropped += 1;
+        }
+        if dropped == 0 {
+            return;
+        }
+
+        super::debug_print(format!(
+            "event log over its limits, dropping {:?} oldest events",
+            dropped
+        ));
+        write_lines(&self.path, &lines[dropped..]);
 
-        if !self.path.exists() {
-            fs::create_dir_all(&self.path.parent().unwrap()).unwrap();
-            fs::File::create(&self.path).unwrap();
+        let dropped_path = self.dropped_path();
+        let total = read_dropped(&dropped_path) + dropped as u64;
+        fs::write(&dropped_path, total.to_string()).unwrap();
+    }
+
+    // Count of events dropped by enforce_limits, since the last take_dropped
+    fn dropped_path(&self) -> path::PathBuf {
+        self.path.with_extension("dropped")
+    }
+
+    #[cfg(test)]
+    pub fn get_events(&self) -> Vec<StoredEvent> {
+        let _lock = self.lock();
+        parse_lines(read_lines(&self.path)).0
+    }
+
+    // Lines that aren't valid events (truncated by a crash, or from
+    // an incompatible version) are moved to events.corrupt.log
+    fn quarantine(&self, corrupt: &[(String, String)]) {
+        let corrupt_path = self.path.with_extension("corrupt.log");
+        super::debug_print(format!(
+            "quarantining {:?} corrupt lines path={:?}",
+            corrupt.len(),
+            corrupt_path
+        ));
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&corrupt_path)
+            .unwrap();
+        for (line, reason) in corrupt {
+            let json = serde_json::json!({
+                "reason": reason,
+                "line": line,
+                "datetime": format!("{}", UTC::now()),
+            });
+            writeln!(file, "{}", json).unwrap();
         }
+    }
+}
+
+impl EventStore for EventLog {
+    fn record_event(&self, event: &StoredEvent) {
+        super::debug_print(format!("appending_event_log path={:?}", self.path));
 
+        let _lock = self.lock();
         let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
             .append(true)
             .open(&self.path)
             .unwrap();
+
+        // If the last write was cut short, start on a new line so
+        // only the truncated event is lost (and quarantined)
+        if file.metadata().unwrap().len() > 0 {
+            let mut last = [0; 1];
+            file.seek(SeekFrom::End(-1)).unwrap();
+            file.read_exact(&mut last).unwrap();
+            if last[0] != b'\n' {
+                writeln!(file).unwrap();
+            }
+        }
+
         let json = serde_json::to_string(&event).unwrap();
         writeln!(file, "{}", json).unwrap();
+        drop(file);
+
+        self.enforce_limits();
     }
 
-    pub fn get_events(&self) -> Vec<Event> {
-        let mut events = Vec::new();
-        if self.path.exists() {
-            let file = fs::File::open(&self.path).unwrap();
-            // file.lines(
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let line = line.unwrap();
-                let event = serde_json::from_str(&line);
-                if event.is_ok() {
-                    events.push(event.unwrap());
-                }
+    fn remove_event(&self, id: &str) -> bool {
+        let _lock = self.lock();
+        let lines = read_lines(&self.path);
+        let is_event = |line: &String| {
+            // Only parse the lines that could be it
+            line.contains(id)
+                && serde_json::from_str::<StoredEvent>(line)
+                    .is_ok_and(|stored| stored.event.id == id)
+        };
+        match lines.iter().position(is_event) {
+            Some(i) => {
+                let mut lines = lines;
+                lines.remove(i);
+                write_lines(&self.path, &lines);
+                true
             }
+            None => false,
         }
-        events
     }
 
-    pub fn clear(&self) {
-        fs::remove_file(&self.path).unwrap();
+    fn take_dropped(&self) -> u64 {
+        let _lock = self.lock();
+        let dropped_path = self.dropped_path();
+        let dropped = read_dropped(&dropped_path);
+        if dropped_path.exists() {
+            fs::remove_file(&dropped_path).unwrap();
+        }
+        dropped
     }
+
+    // Takes every event in the log for sending. Returns None if another
+    // process is already dispatching, since it will have taken them.
+    fn claim_events(&self) -> Option<(Claim, Vec<StoredEvent>)> {
+        fs::create_dir_all(self.path.parent().unwrap()).unwrap();
+        let claim_lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("dispatch.lock"))
+            .unwrap();
+        if claim_lock.try_lock_exclusive().is_err() {
+            return None;
+        }
+
+        let _lock = self.lock();
+        let dispatching_path = self.dispatching_path();
+
+        // Anything left over in the dispatching file is from a dispatch
+        // that never finished (the process died), so it's sent again too
+        let mut lines = read_lines(&dispatching_path);
+        lines.extend(read_lines(&self.path));
+
+        let (events, corrupt) = parse_lines(lines);
+        if !corrupt.is_empty() {
+            self.quarantine(&corrupt);
+        }
+
+        let lines: Vec<String> = events
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect();
+        write_lines(&dispatching_path, &lines);
+        if self.path.exists() {
+            fs::remove_file(&self.path).unwrap();
+        }
+
+        Some((Claim::File { _lock: claim_lock }, events))
+    }
+
+    // Puts the events that weren't sent back in the log, ahead of
+    // anything that was recorded while the dispatch was running
+    fn release_claim(&self, claim: Claim, remaining: &[StoredEvent]) {
+        super::debug_print(format!(
+            "releasing_event_log path={:?} remaining={:?}",
+            self.path,
+            remaining.len()
+        ));
+
+        let _lock = self.lock();
+        let mut lines: Vec<String> = remaining
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect();
+        lines.extend(read_lines(&self.path));
+        write_lines(&self.path, &lines);
+        self.enforce_limits();
+
+        let dispatching_path = self.dispatching_path();
+        if dispatching_path.exists() {
+            fs::remove_file(&dispatching_path).unwrap();
+        }
+        drop(claim);
+    }
+}
+
+fn read_lines(path: &path::Path) -> Vec<String> {
+    if !path.exists() {
+        return Vec::new();
+    }
+    let file = fs::File::open(path).unwrap();
+    // Lossy, so a line with broken UTF-8 still makes it to quarantine
+    BufReader::new(file)
+        .split(b'\n')
+        .map(|line| String::from_utf8_lossy(&line.unwrap()).into_owned())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+fn read_dropped(path: &path::Path) -> u64 {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| contents.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// Returns the events, and any lines that couldn't be parsed along with why
+fn parse_lines(lines: Vec<String>) -> (Vec<StoredEvent>, Vec<(String, String)>) {
+    let mut events = Vec::new();
+    let mut corrupt = Vec::new();
+    for line in lines {
+        match serde_json::from_str(&line) {
+            Ok(event) => events.push(event),
+            Err(err) => corrupt.push((line, err.to_string())),
+        }
+    }
+    (events, corrupt)
+}
+
+// Atomically swaps the file contents by writing to a temporary
+// file and renaming it into place
+fn write_lines(path: &path::Path, lines: &[String]) {
+    if lines.is_empty() {
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+        return;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path).unwrap();
+    for line in lines {
+        writeln!(file, "{}", line).unwrap();
+    }
+    file.sync_all().unwrap();
+    fs::rename(&tmp_path, path).unwrap();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{test_event, test_stored_event, TempDir};
 
     #[test]
     fn deserialize_standard() {
@@ -127,23 +468,208 @@ mod tests {
         )
         .unwrap();
         assert_eq!(event.ci, false);
+        assert_eq!(event.id.len(), 36);
     }
     #[test]
     fn serialize() {
-        let mut event = Event::new(
-            "test",
+        let mut event = test_event("test");
+        event.id = "test".to_string();
+        event.datetime = "test".to_string();
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":"test","slug":"test","type":"test","metadata":null,"user_id":"test","invocation_id":"test","ci":false,"datetime":"test","version":"1.0.0"}"#
+        );
+    }
+    #[test]
+    fn stored_event() {
+        let mut stored = test_stored_event("test");
+        stored.attempts = 2;
+        stored.next_attempt_at = Some(100);
+
+        // Saved flat, the same as lines logged before StoredEvent
+        let line = serde_json::to_string(&stored).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["slug"], "test");
+        assert_eq!(json["attempts"], 2);
+        let read: StoredEvent = serde_json::from_str(&line).unwrap();
+        assert_eq!(read.attempts, 2);
+        assert_eq!(read.next_attempt_at, Some(100));
+        assert_eq!(read.event.id, stored.event.id);
+
+        let read: StoredEvent = serde_json::from_str(
+            r#"{"slug": "test", "type_s": "test", "metadata": {}, "user_id": "test",
+                "invocation_id": "test", "datetime": "test", "attempts": 1}"#,
+        )
+        .unwrap();
+        assert_eq!(read.event.type_s, "test");
+        assert_eq!(read.attempts, 1);
+
+        // The bookkeeping isn't sent to the API
+        let json = serde_json::to_value(&read.event).unwrap();
+        assert!(json.get("attempts").is_none());
+        assert!(json.get("next_attempt_at").is_none());
+    }
+    #[test]
+    fn truncate_metadata() {
+        let event = Event::new(
             "test",
-            serde_json::Value::Null,
+            "error",
+            serde_json::json!({"stacktrace": "x".repeat(100)}),
             "test",
             "test",
             &false,
             "1.0.0",
+            &MetadataLimits {
+                max_string_bytes: 10,
+                ..MetadataLimits::default()
+            },
         );
-        event.datetime = "test".to_string();
-        let json = serde_json::to_string(&event).unwrap();
         assert_eq!(
-            json,
-            r#"{"slug":"test","type":"test","metadata":null,"user_id":"test","invocation_id":"test","ci":false,"datetime":"test","version":"1.0.0"}"#
+            event.metadata["stacktrace"],
+            format!("{}…[truncated 90 bytes]", "x".repeat(10))
+        );
+        assert_eq!(event.truncated, vec!["metadata.stacktrace"]);
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.contains(r#""truncated":["metadata.stacktrace"]"#));
+    }
+    #[test]
+    fn claim_events() {
+        let dir = TempDir::new();
+        let log = EventLog::new(
+            dir.path(),
+            &LogLimits {
+                max_bytes: DEFAULT_MAX_LOG_BYTES,
+                max_events: DEFAULT_MAX_LOG_EVENTS,
+            },
+        );
+
+        let mut event = test_stored_event("test");
+        log.record_event(&event);
+        log.record_event(&event);
+
+        let (claim, events) = log.claim_events().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(log.get_events().is_empty());
+
+        // Only one dispatch at a time
+        assert!(log.claim_events().is_none());
+
+        // Recorded while the claimed events were being sent
+        event.event.slug = "new".to_string();
+        log.record_event(&event);
+
+        let mut failed = events[0].clone();
+        failed.attempts = 3;
+        log.release_claim(claim, &[failed]);
+
+        let events = log.get_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].attempts, 3);
+        assert_eq!(events[0].event.id, event.event.id);
+        assert_eq!(events[1].event.slug, "new");
+
+        let (claim, events) = log.claim_events().unwrap();
+        assert_eq!(events.len(), 2);
+        log.release_claim(claim, &[]);
+        assert!(log.get_events().is_empty());
+    }
+
+    #[test]
+    fn remove_event() {
+        let dir = TempDir::new();
+        let log = EventLog::new(
+            dir.path(),
+            &LogLimits {
+                max_bytes: DEFAULT_MAX_LOG_BYTES,
+                max_events: DEFAULT_MAX_LOG_EVENTS,
+            },
+        );
+        let sent = test_stored_event("sent");
+        log.record_event(&test_stored_event("first"));
+        log.record_event(&sent);
+        log.record_event(&test_stored_event("last"));
+
+        assert!(log.remove_event(&sent.event.id));
+        assert!(!log.remove_event(&sent.event.id));
+        assert!(!log.remove_event("unknown"));
+        let slugs: Vec<String> = log.get_events().into_iter().map(|e| e.event.slug).collect();
+        assert_eq!(slugs, vec!["first", "last"]);
+    }
+
+    #[test]
+    fn log_limits() {
+        let dir = TempDir::new();
+        let mut event = test_stored_event("test");
+        let event_bytes = serde_json::to_string(&event).unwrap().len() as u64 + 1;
+
+        let log = EventLog::new(
+            dir.path(),
+            &LogLimits {
+                max_bytes: DEFAULT_MAX_LOG_BYTES,
+                max_events: 3,
+            },
         );
+        for i in 0..5 {
+            event.event.slug = i.to_string();
+            log.record_event(&event);
+        }
+        let slugs: Vec<String> = log.get_events().into_iter().map(|e| e.event.slug).collect();
+        assert_eq!(slugs, vec!["2", "3", "4"]);
+        assert_eq!(log.take_dropped(), 2);
+        assert_eq!(log.take_dropped(), 0);
+
+        let log = EventLog::new(
+            dir.path(),
+            &LogLimits {
+                max_bytes: event_bytes * 2,
+                max_events: DEFAULT_MAX_LOG_EVENTS,
+            },
+        );
+        log.record_event(&event);
+        assert_eq!(log.get_events().len(), 2);
+        assert_eq!(log.take_dropped(), 2);
+    }
+
+    #[test]
+    fn quarantine_corrupt_lines() {
+        let dir = TempDir::new();
+        let log = EventLog::new(
+            dir.path(),
+            &LogLimits {
+                max_bytes: DEFAULT_MAX_LOG_BYTES,
+                max_events: DEFAULT_MAX_LOG_EVENTS,
+            },
+        );
+        let event = test_stored_event("test");
+        log.record_event(&event);
+        // A write cut short by a crash
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&log.path)
+            .unwrap()
+            .write_all(b"{\"slug\":\"te")
+            .unwrap();
+        log.record_event(&event);
+
+        let (claim, events) = log.claim_events().unwrap();
+        assert_eq!(events.len(), 2);
+        log.release_claim(claim, &events);
+
+        let corrupt = fs::read_to_string(dir.join("events.corrupt.log")).unwrap();
+        let corrupt: Vec<serde_json::Value> = corrupt
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(corrupt.len(), 1);
+        assert_eq!(corrupt[0]["line"], "{\"slug\":\"te");
+        assert!(corrupt[0]["reason"].as_str().unwrap().contains("EOF"));
+
+        // Quarantined lines are gone from the log itself
+        let (claim, events) = log.claim_events().unwrap();
+        assert_eq!(events.len(), 2);
+        log.release_claim(claim, &[]);
+        let corrupt = fs::read_to_string(dir.join("events.corrupt.log")).unwrap();
+        assert_eq!(corrupt.lines().count(), 1);
     }
 }
@@ -1,14 +1,32 @@
 use libc::c_char;
 use serde_json;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::time::Duration;
 
 mod api;
+mod ci;
+mod consent;
+mod context;
 mod events;
+mod redact;
+mod retry;
+mod schema;
 mod settings;
-
-use api::APIClient;
-use events::{Event, EventLog};
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+mod template;
+#[cfg(test)]
+mod testing;
+mod transport;
+mod truncate;
+mod worker;
+
+pub use events::Event;
+use events::{LogLimits, StoredEvent};
+use retry::{Outcome, RetryPolicy};
 use settings::Settings;
+use truncate::MetadataLimits;
 
 // http://jakegoulding.com/rust-ffi-omnibus/string_arguments/
 // https://docs.rs/ffi-support/0.4.4/src/ffi_support/ffistr.rs.html#144
@@ -38,15 +56,42 @@ fn parse_ffi_bool(i: u32) -> bool {
 
 use once_cell::sync::Lazy;
 
+// How long dispatch_events waits for the sender thread before reading the EventLog
+const DISPATCH_FLUSH_TIMEOUT_MS: u64 = 5000;
+
 // Efectively the "global" settings variable
 static mut SETTINGS: Lazy<Settings> = Lazy::new(|| Settings::new());
 
 pub fn debug_print(s: String) {
-    if unsafe { SETTINGS.get_debug() } {
+    if settings::get_debug() {
         println!("CLS: {}", s);
     }
 }
 
+// Reported by dispatch_events when the EventLog had to drop events to stay within its limits
+const DROPPED_EVENTS_SLUG: &str = "cls_events_dropped";
+const DROPPED_EVENTS_TYPE: &str = "cls";
+
+fn new_event(slug: &str, type_s: &str, metadata: serde_json::Value) -> Event {
+    let invocation_id = unsafe { SETTINGS.get_invocation_id() };
+    let user_id = unsafe { SETTINGS.get_user_id() };
+    let version = unsafe { SETTINGS.version.as_str() };
+    let ci = unsafe { SETTINGS.get_is_ci() };
+    let metadata_limits = unsafe { SETTINGS.get_metadata_limits() };
+    let mut event = Event::new(
+        slug,
+        type_s,
+        metadata,
+        &user_id,
+        &invocation_id,
+        &ci,
+        version,
+        &metadata_limits,
+    );
+    event.ci_provider = unsafe { SETTINGS.get_ci_provider() };
+    event
+}
+
 #[no_mangle]
 pub extern "C" fn track_event(
     slug: *const c_char,
@@ -60,7 +105,8 @@ pub extern "C" fn track_event(
 
     let slug = parse_ffi_str(slug);
     let type_s = parse_ffi_str(type_s);
-    let metadata = parse_ffi_json(metadata);
+    // Scrubbed before anything else sees it, including the debug output
+    let metadata = unsafe { SETTINGS.get_redactor() }.redact(parse_ffi_json(metadata));
     let dispatch = parse_ffi_bool(dispatch);
 
     debug_print(format!(
@@ -68,19 +114,16 @@ pub extern "C" fn track_event(
         slug, type_s, metadata, dispatch
     ));
 
-    let invocation_id = unsafe { SETTINGS.get_invocation_id() };
-    let user_id = unsafe { SETTINGS.get_user_id() };
-    let version = unsafe { SETTINGS.version.as_str() };
-    let ci = unsafe { SETTINGS.get_is_ci() };
-    let event = Event::new(
-        &slug,
-        &type_s,
-        metadata,
-        &user_id,
-        &invocation_id,
-        &ci,
-        &version,
-    );
+    // Instrumentation bugs are caught here, before bad data reaches the backend
+    if let Err(errors) = unsafe { SETTINGS.validate_event(&slug, &type_s, &metadata) } {
+        debug_print(format!(
+            "track_event rejected invalid event slug={:?} errors={:?}",
+            slug, errors
+        ));
+        return;
+    }
+
+    let mut event = new_event(&slug, &type_s, metadata);
 
     let should_track = match unsafe { SETTINGS.should_track_event(&event) } {
         Ok(val) => val,
@@ -89,49 +132,152 @@ pub extern "C" fn track_event(
     if !should_track {
         return;
     }
+    event.context = unsafe { SETTINGS.collect_context() };
 
-    let mut should_record = !dispatch;
+    // Logged even when it's about to be sent, so it isn't lost if the
+    // process exits first. The sender thread removes it once it's sent.
+    let event = StoredEvent::new(event);
+    let log = unsafe { SETTINGS.get_event_log() };
+    log.record_event(&event);
 
-    if dispatch {
-        let token = unsafe { SETTINGS.get_project_key() };
-        let api = APIClient::new(&token);
-        if api.post_event(&event).is_err() {
-            should_record = true
-        }
+    if !dispatch {
+        return;
     }
 
-    if should_record {
-        let log = EventLog::new(unsafe { &SETTINGS.get_cache_dir() });
-        log.record_event(&event);
+    let transport = match unsafe { SETTINGS.get_transport_config() } {
+        Ok(transport) => transport,
+        Err(err) => {
+            debug_print(format!("track_event {}", err));
+            return;
+        }
+    };
+
+    // Hand off to the sender thread so the caller doesn't wait on the network
+    let job = worker::Job {
+        category: unsafe { SETTINGS.get_event_category(&event.event.type_s) },
+        event,
+        transport,
+        retry_policy: unsafe { SETTINGS.get_retry_policy() },
+        log,
+        dead_letter: unsafe { SETTINGS.get_dead_letter_log() },
+    };
+    if worker::send(job).is_err() {
+        debug_print("sender queue full, leaving event in the event log".to_string());
     }
 }
 
+// Blocks until events passed to track_event with dispatch=1 have been sent.
+// Anything that isn't sent in time stays in the log for dispatch_events.
+#[no_mangle]
+pub extern "C" fn flush_events(timeout_ms: u32) {
+    debug_print(format!("flush_events timeout_ms={:?}", timeout_ms));
+    worker::flush(Duration::from_millis(timeout_ms.into()));
+}
+
 #[no_mangle]
 pub extern "C" fn dispatch_events() {
     debug_print("dispatch_events".to_string());
-    let log = EventLog::new(unsafe { &SETTINGS.get_cache_dir() });
-    let events = log.get_events();
-    let token = unsafe { SETTINGS.get_project_key() };
-    let api = APIClient::new(&token);
-
-    let mut events_failed = Vec::new();
-    let mut events_succeded = Vec::new();
-
-    for event in events {
-        match api.post_event(&event) {
-            Ok(_) => events_succeded.push(event),
-            Err(_) => events_failed.push(event),
+
+    // Let the sender thread finish first, so anything it
+    // fails to send is included in this dispatch
+    worker::flush(Duration::from_millis(DISPATCH_FLUSH_TIMEOUT_MS));
+
+    let log = unsafe { SETTINGS.get_event_log() };
+    let (claim, mut events) = match log.claim_events() {
+        Some(claimed) => claimed,
+        None => {
+            debug_print("events are already being dispatched by another process".to_string());
+            return;
         }
+    };
+
+    // Let the backend know if the log filled up while we were offline,
+    // this goes through the same retries as everything else
+    let dropped = log.take_dropped();
+    if dropped > 0 {
+        let mut event = new_event(
+            DROPPED_EVENTS_SLUG,
+            DROPPED_EVENTS_TYPE,
+            serde_json::json!({ "dropped_events": dropped }),
+        );
+        event.context = unsafe { SETTINGS.collect_context() };
+        events.push(StoredEvent::new(event));
+    }
+
+    // Consent is checked again, the user may have opted out since these were stored
+    let events = without_declined(events, &unsafe { SETTINGS.get_declined_categories() });
+
+    if events.is_empty() {
+        log.release_claim(claim, &[]);
+        return;
     }
-    if events_failed.len() > 0 {
+
+    let transport = unsafe { SETTINGS.get_transport_config() }.and_then(|config| config.build());
+    let transport = match transport {
+        Ok(transport) => transport,
+        Err(err) => {
+            debug_print(format!("dispatch_events {}", err));
+            log.release_claim(claim, &events);
+            return;
+        }
+    };
+    let retry_policy = unsafe { SETTINGS.get_retry_policy() };
+
+    // Events still backing off from an earlier failure wait for a later dispatch
+    let (events_due, mut events_remaining): (Vec<StoredEvent>, Vec<StoredEvent>) =
+        events.into_iter().partition(retry::is_due);
+    let mut events_dead = Vec::new();
+
+    // Only the API fields are sent, the chunks line up with events_due
+    let events_to_send: Vec<Event> = events_due.iter().map(|e| e.event.clone()).collect();
+
+    // Events after the last chunk we tried are kept as-is
+    let mut events_tried = 0;
+
+    for (chunk, result) in transport.send(&events_to_send) {
+        let chunk = &events_due[events_tried..events_tried + chunk.len()];
+        events_tried += chunk.len();
+
+        let err = match result {
+            Ok(_) => continue,
+            Err(err) => err,
+        };
         debug_print(format!(
-            "{:?} events failed to dispatch",
-            events_failed.len()
+            "dispatch failed for {:?} events: {}",
+            chunk.len(),
+            err
         ));
+
+        for event in chunk {
+            match retry_policy.handle_error(event.clone(), &err) {
+                Outcome::Requeue(event) => events_remaining.push(event),
+                Outcome::DeadLetter(event) => events_dead.push(event),
+            }
+        }
+
+        if err.should_pause() {
+            debug_print("pausing dispatch until the next run".to_string());
+            break;
+        }
     }
-    if events_succeded.len() > 0 {
-        log.clear();
+    events_remaining.extend_from_slice(&events_due[events_tried..]);
+
+    if !events_dead.is_empty() {
+        debug_print(format!(
+            "{:?} events can't be sent, moving to dead letter log",
+            events_dead.len()
+        ));
+        let dead_letter = unsafe { SETTINGS.get_dead_letter_log() };
+        for event in &events_dead {
+            dead_letter.record_event(event);
+        }
     }
+    debug_print(format!(
+        "{:?} events remaining after dispatch",
+        events_remaining.len()
+    ));
+
+    log.release_claim(claim, &events_remaining);
 }
 
 #[no_mangle]
@@ -226,6 +372,236 @@ pub extern "C" fn set_error_prompt(text: *const c_char) {
     }
 }
 
+#[no_mangle]
+pub extern "C" fn set_consent_policy(version: u32, changes: *const c_char) {
+    if changes.is_null() {
+        // Silently return
+        return;
+    }
+
+    let changes = parse_ffi_str(changes);
+    unsafe {
+        SETTINGS.set_consent_policy(version, changes.as_str());
+        debug_print(format!(
+            "set_consent_policy version={:?} changes={:?}",
+            SETTINGS.get_policy_version(),
+            SETTINGS.get_policy_changes()
+        ))
+    }
+}
+
+// Events the user has turned off since they were stored, leaving the rest
+fn without_declined(events: Vec<StoredEvent>, declined: &[consent::Category]) -> Vec<StoredEvent> {
+    if declined.is_empty() {
+        return events;
+    }
+    let count = events.len();
+    let events: Vec<StoredEvent> = events
+        .into_iter()
+        .filter(|event| {
+            let category = unsafe { SETTINGS.get_event_category(&event.event.type_s) };
+            !declined.contains(&category)
+        })
+        .collect();
+    if events.len() < count {
+        debug_print(format!(
+            "dropping {:?} events the user has opted out of",
+            count - events.len()
+        ));
+    }
+    events
+}
+
+// Clears out queued and stored events once the user turns tracking off,
+// instead of sending them on the next dispatch
+fn discard_declined_events() {
+    let declined = unsafe { SETTINGS.get_declined_categories() };
+    if declined.is_empty() {
+        return;
+    }
+    worker::discard(&declined);
+
+    let log = unsafe { SETTINGS.get_event_log() };
+    match log.claim_events() {
+        Some((claim, events)) => log.release_claim(claim, &without_declined(events, &declined)),
+        // That dispatch checks consent itself
+        None => debug_print("events are already being dispatched by another process".to_string()),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_tracking_enabled(enabled: u32) {
+    let enabled = parse_ffi_bool(enabled);
+    debug_print(format!("set_tracking_enabled enabled={:?}", enabled));
+    unsafe {
+        SETTINGS.set_tracking_enabled(enabled);
+    }
+    if !enabled {
+        discard_declined_events();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_category_enabled(category: *const c_char, enabled: u32) {
+    if category.is_null() {
+        // Silently return
+        return;
+    }
+
+    let category = parse_ffi_str(category);
+    let enabled = parse_ffi_bool(enabled);
+    match consent::Category::parse(&category) {
+        Ok(category) => unsafe {
+            debug_print(format!(
+                "set_category_enabled category={:?} enabled={:?}",
+                category, enabled
+            ));
+            SETTINGS.set_category_enabled(category, enabled);
+            if !enabled {
+                discard_declined_events();
+            }
+        },
+        Err(err) => debug_print(format!("set_category_enabled {}", err)),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn reset_consent() {
+    debug_print("reset_consent".to_string());
+    unsafe {
+        SETTINGS.reset_consent();
+    }
+}
+
+// Returns a JSON string, which has to be passed back to free_string
+#[no_mangle]
+pub extern "C" fn get_tracking_status() -> *mut c_char {
+    let status = unsafe { SETTINGS.get_tracking_status() };
+    debug_print(format!("get_tracking_status status={}", status));
+    CString::new(status.to_string()).unwrap().into_raw()
+}
+
+// Only for strings returned by this library
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn free_string(s: *mut c_char) {
+    if s.is_null() {
+        // Silently return
+        return;
+    }
+
+    unsafe {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_categories_prompt(text: *const c_char) {
+    if text.is_null() {
+        // Silently return
+        return;
+    }
+
+    let text = parse_ffi_str(text);
+    unsafe {
+        SETTINGS.categories_prompt = text;
+        debug_print(format!(
+            "set_categories_prompt text={:?}",
+            SETTINGS.categories_prompt
+        ))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_event_category(type_s: *const c_char, category: *const c_char) {
+    if type_s.is_null() || category.is_null() {
+        // Silently return
+        return;
+    }
+
+    let type_s = parse_ffi_str(type_s);
+    let category = parse_ffi_str(category);
+    match consent::Category::parse(&category) {
+        Ok(category) => unsafe {
+            SETTINGS.set_event_category(&type_s, category);
+            debug_print(format!(
+                "set_event_category type={:?} category={:?}",
+                type_s,
+                SETTINGS.get_event_category(&type_s)
+            ))
+        },
+        Err(err) => debug_print(format!("set_event_category {}", err)),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_consent_categories(spec: *const c_char) {
+    if spec.is_null() {
+        // Silently return
+        return;
+    }
+
+    let spec = parse_ffi_str(spec);
+    unsafe {
+        SETTINGS.set_consent_categories(spec.as_str());
+        debug_print(format!(
+            "set_consent_categories spec={:?}",
+            SETTINGS.get_consent_categories()
+        ))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_prompt_mode(mode: *const c_char) {
+    if mode.is_null() {
+        // Silently return
+        return;
+    }
+
+    let mode = parse_ffi_str(mode);
+    unsafe {
+        SETTINGS.set_prompt_mode(mode.as_str());
+        debug_print(format!(
+            "set_prompt_mode mode={:?}",
+            SETTINGS.get_prompt_mode()
+        ))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_privacy_policy_url(url: *const c_char) {
+    if url.is_null() {
+        // Silently return
+        return;
+    }
+
+    let url = parse_ffi_str(url);
+    unsafe {
+        SETTINGS.set_privacy_policy_url(url.as_str());
+        debug_print(format!(
+            "set_privacy_policy_url url={:?}",
+            SETTINGS.get_privacy_policy_url()
+        ))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_opt_out_command(command: *const c_char) {
+    if command.is_null() {
+        // Silently return
+        return;
+    }
+
+    let command = parse_ffi_str(command);
+    unsafe {
+        SETTINGS.set_opt_out_command(command.as_str());
+        debug_print(format!(
+            "set_opt_out_command command={:?}",
+            SETTINGS.get_opt_out_command()
+        ))
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn set_debug(debug: u32) {
     let debug = parse_ffi_bool(debug);
@@ -235,6 +611,207 @@ pub extern "C" fn set_debug(debug: u32) {
     }
 }
 
+#[no_mangle]
+pub extern "C" fn set_retry_policy(max_attempts: u32, base_delay: u32, max_delay: u32) {
+    unsafe {
+        SETTINGS.set_retry_policy(RetryPolicy {
+            max_attempts,
+            base_delay: base_delay.into(),
+            max_delay: max_delay.into(),
+        });
+        debug_print(format!(
+            "set_retry_policy policy={:?}",
+            SETTINGS.get_retry_policy()
+        ));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_gzip(gzip: u32) {
+    let gzip = parse_ffi_bool(gzip);
+    unsafe {
+        SETTINGS.set_gzip(gzip);
+        debug_print(format!("set_gzip gzip={:?}", SETTINGS.get_gzip()));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_transport(spec: *const c_char) {
+    if spec.is_null() {
+        // Silently return
+        return;
+    }
+
+    let spec = parse_ffi_str(spec);
+    unsafe {
+        SETTINGS.set_transport(spec.as_str());
+        debug_print(format!("set_transport spec={:?}", SETTINGS.get_transport()))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_api_timeout(seconds: u32) {
+    unsafe {
+        SETTINGS.set_api_timeout(seconds.into());
+        debug_print(format!(
+            "set_api_timeout seconds={:?}",
+            SETTINGS.get_api_timeout()
+        ));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_proxy(url: *const c_char) {
+    if url.is_null() {
+        // Silently return
+        return;
+    }
+
+    let url = parse_ffi_str(url);
+    unsafe {
+        SETTINGS.set_proxy(url.as_str());
+        debug_print(format!("set_proxy url={:?}", SETTINGS.get_proxy()))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_ca_bundle(path: *const c_char) {
+    if path.is_null() {
+        // Silently return
+        return;
+    }
+
+    let path = parse_ffi_str(path);
+    unsafe {
+        SETTINGS.set_ca_bundle(path.as_str());
+        debug_print(format!("set_ca_bundle path={:?}", SETTINGS.get_ca_bundle()))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_log_limits(max_bytes: u32, max_events: u32) {
+    unsafe {
+        SETTINGS.set_log_limits(LogLimits {
+            max_bytes: max_bytes.into(),
+            max_events: max_events as usize,
+        });
+        debug_print(format!(
+            "set_log_limits limits={:?}",
+            SETTINGS.get_log_limits()
+        ));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_metadata_limits(
+    max_bytes: u32,
+    max_string_bytes: u32,
+    max_array_items: u32,
+    max_depth: u32,
+) {
+    unsafe {
+        SETTINGS.set_metadata_limits(MetadataLimits {
+            max_bytes: max_bytes as usize,
+            max_string_bytes: max_string_bytes as usize,
+            max_array_items: max_array_items as usize,
+            max_depth: max_depth as usize,
+        });
+        debug_print(format!(
+            "set_metadata_limits limits={:?}",
+            SETTINGS.get_metadata_limits()
+        ));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn add_redaction_pattern(pattern: *const c_char) {
+    if pattern.is_null() {
+        // Silently return
+        return;
+    }
+
+    let pattern = parse_ffi_str(pattern);
+    unsafe {
+        match SETTINGS.add_redaction_pattern(pattern.as_str()) {
+            Ok(()) => debug_print(format!(
+                "add_redaction_pattern patterns={:?}",
+                SETTINGS.get_redaction_patterns()
+            )),
+            Err(err) => debug_print(format!("add_redaction_pattern {}", err)),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_context(items: *const c_char) {
+    if items.is_null() {
+        // Silently return
+        return;
+    }
+
+    let items = parse_ffi_str(items);
+    unsafe {
+        SETTINGS.set_context(items.as_str());
+        debug_print(format!("set_context items={:?}", SETTINGS.get_context()))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_event_store(spec: *const c_char) {
+    if spec.is_null() {
+        // Silently return
+        return;
+    }
+
+    let spec = parse_ffi_str(spec);
+    unsafe {
+        SETTINGS.set_event_store(spec.as_str());
+        debug_print(format!(
+            "set_event_store spec={:?}",
+            SETTINGS.get_event_store()
+        ))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn register_event_schema(
+    slug: *const c_char,
+    type_s: *const c_char,
+    metadata_schema: *const c_char,
+) {
+    if slug.is_null() || type_s.is_null() || metadata_schema.is_null() {
+        // Silently return
+        return;
+    }
+
+    let schema = schema::EventSchema {
+        slug: parse_ffi_str(slug),
+        type_s: parse_ffi_str(type_s),
+        metadata: Some(parse_ffi_json(metadata_schema)),
+    };
+    debug_print(format!("register_event_schema schema={:?}", schema));
+    unsafe {
+        SETTINGS.register_event_schema(schema);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_event_catalog(path: *const c_char) {
+    if path.is_null() {
+        // Silently return
+        return;
+    }
+
+    let path = parse_ffi_str(path);
+    unsafe {
+        SETTINGS.set_event_catalog(path.as_str());
+        debug_print(format!(
+            "set_event_catalog path={:?}",
+            SETTINGS.get_event_catalog()
+        ))
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn set_is_ci(is_ci: u32) {
     let is_ci = parse_ffi_bool(is_ci);
@@ -257,6 +834,23 @@ pub extern "C" fn set_ci_tracking_enabled(enabled: u32) {
     }
 }
 
+#[no_mangle]
+pub extern "C" fn set_ci_tracking_providers(spec: *const c_char) {
+    if spec.is_null() {
+        // Silently return
+        return;
+    }
+
+    let spec = parse_ffi_str(spec);
+    unsafe {
+        SETTINGS.set_ci_tracking_providers(spec.as_str());
+        debug_print(format!(
+            "set_ci_tracking_providers spec={:?}",
+            SETTINGS.get_ci_tracking_providers()
+        ))
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn set_user_id(user_id: *const c_char) {
     if user_id.is_null() {
@@ -0,0 +1,174 @@
+use crate::api::ApiError;
+use crate::events::StoredEvent;
+use chrono::UTC;
+use rand::Rng;
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+pub const DEFAULT_BASE_DELAY: u64 = 60; // seconds
+pub const DEFAULT_MAX_DELAY: u64 = 60 * 60 * 24;
+
+// Where an event goes after the API didn't accept it
+pub enum Outcome {
+    Requeue(StoredEvent),
+    DeadLetter(StoredEvent),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: u64,
+    pub max_delay: u64,
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+
+    // Seconds to wait after the given number of failed attempts.
+    // Doubles every attempt up to max_delay, then picks a random point in the
+    // upper half so a fleet of machines that went offline together don't
+    // all come back at the same moment.
+    pub fn backoff(&self, attempts: u32) -> u64 {
+        let exponent = attempts.saturating_sub(1).min(32);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        if delay == 0 {
+            return 0;
+        }
+        delay / 2 + rand::thread_rng().gen_range(0..=delay - delay / 2)
+    }
+
+    // Counts a failed attempt and schedules the next one, no sooner than retry_after.
+    // Returns false once the event has used up its attempts and should be dead-lettered.
+    pub fn record_failure(&self, event: &mut StoredEvent, retry_after: Option<u64>) -> bool {
+        event.attempts += 1;
+        if event.attempts >= self.max_attempts {
+            event.next_attempt_at = None;
+            return false;
+        }
+        let delay = self.backoff(event.attempts).max(retry_after.unwrap_or(0));
+        event.next_attempt_at = Some(UTC::now().timestamp() + delay as i64);
+        true
+    }
+
+    pub fn handle_error(&self, mut event: StoredEvent, err: &ApiError) -> Outcome {
+        match err {
+            // Nothing wrong with the event itself, so it keeps its attempts
+            // until the project key or client settings are fixed
+            ApiError::Unauthorized(_) | ApiError::Misconfigured(_) | ApiError::Config(_) => {
+                Outcome::Requeue(event)
+            }
+            _ if !err.is_retryable() => Outcome::DeadLetter(event),
+            _ => {
+                if self.record_failure(&mut event, err.retry_after()) {
+                    Outcome::Requeue(event)
+                } else {
+                    Outcome::DeadLetter(event)
+                }
+            }
+        }
+    }
+}
+
+// Whether an event's backoff has passed and it can be sent again
+pub fn is_due(event: &StoredEvent) -> bool {
+    match event.next_attempt_at {
+        Some(timestamp) => timestamp <= UTC::now().timestamp(),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_stored_event;
+
+    #[test]
+    fn backoff_bounds() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: 10,
+            max_delay: 100,
+        };
+        for _ in 0..100 {
+            let delay = policy.backoff(1);
+            assert!((5..=10).contains(&delay));
+            let delay = policy.backoff(3);
+            assert!((20..=40).contains(&delay));
+            let delay = policy.backoff(50);
+            assert!((50..=100).contains(&delay));
+        }
+    }
+
+    #[test]
+    fn record_failure() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: 10,
+            max_delay: 100,
+        };
+        let mut event = test_stored_event("test");
+        assert!(is_due(&event));
+
+        assert!(policy.record_failure(&mut event, None));
+        assert_eq!(event.attempts, 1);
+        assert!(!is_due(&event));
+
+        assert!(!policy.record_failure(&mut event, None));
+        assert_eq!(event.attempts, 2);
+        assert_eq!(event.next_attempt_at, None);
+    }
+
+    #[test]
+    fn handle_error() {
+        let policy = RetryPolicy::new();
+
+        let outcome = policy.handle_error(
+            test_stored_event("test"),
+            &ApiError::RateLimited(Some(100_000)),
+        );
+        match outcome {
+            Outcome::Requeue(event) => {
+                assert_eq!(event.attempts, 1);
+                assert!(event.next_attempt_at.unwrap() >= UTC::now().timestamp() + 100_000);
+            }
+            Outcome::DeadLetter(_) => panic!("rate limited events should be retried"),
+        }
+
+        let outcome = policy.handle_error(
+            test_stored_event("test"),
+            &ApiError::Unauthorized(reqwest::StatusCode::UNAUTHORIZED),
+        );
+        match outcome {
+            Outcome::Requeue(event) => assert_eq!(event.attempts, 0),
+            Outcome::DeadLetter(_) => panic!("unauthorized events should be kept"),
+        }
+
+        // A wrong API URL isn't the events' fault either
+        let outcome = policy.handle_error(
+            test_stored_event("test"),
+            &ApiError::Misconfigured(reqwest::StatusCode::NOT_FOUND),
+        );
+        match outcome {
+            Outcome::Requeue(event) => assert_eq!(event.attempts, 0),
+            Outcome::DeadLetter(_) => panic!("misconfigured events should be kept"),
+        }
+
+        let outcome = policy.handle_error(
+            test_stored_event("test"),
+            &ApiError::Rejected(reqwest::StatusCode::BAD_REQUEST, "".to_string()),
+        );
+        assert!(matches!(outcome, Outcome::DeadLetter(_)));
+
+        // A single event over the size limit isn't permanently rejected
+        let outcome = policy.handle_error(test_stored_event("test"), &ApiError::TooLarge);
+        assert!(matches!(outcome, Outcome::Requeue(_)));
+    }
+}
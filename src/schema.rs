@@ -0,0 +1,310 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+
+// An event the project has said it sends, and what its metadata looks like
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct EventSchema {
+    pub slug: String,
+    #[serde(rename = "type")]
+    pub type_s: String,
+    // JSON Schema for the metadata, anything goes if missing
+    #[serde(default)]
+    pub metadata: Option<Value>,
+}
+
+// The catalog of allowed events, registered through register_event_schema or
+// loaded from a catalog file (a JSON array of schemas). Until anything is
+// registered every event is allowed, so projects can opt in.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    // Keyed by slug and type, since a slug can be tracked as a command and an error
+    schemas: HashMap<(String, String), EventSchema>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    pub fn register(&mut self, schema: EventSchema) {
+        let key = (schema.slug.clone(), schema.type_s.clone());
+        self.schemas.insert(key, schema);
+    }
+
+    pub fn contains(&self, slug: &str, type_s: &str) -> bool {
+        self.schemas
+            .contains_key(&(slug.to_string(), type_s.to_string()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schemas.is_empty()
+    }
+
+    pub fn load_catalog(&mut self, path: &path::Path) -> Result<(), String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("unable to read event catalog {:?}: {}", path, err))?;
+        let schemas: Vec<EventSchema> = serde_json::from_str(&contents)
+            .map_err(|err| format!("invalid event catalog {:?}: {}", path, err))?;
+        for schema in schemas {
+            self.register(schema);
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self, slug: &str, type_s: &str, metadata: &Value) -> Result<(), Vec<String>> {
+        if self.schemas.is_empty() {
+            return Ok(());
+        }
+
+        let key = (slug.to_string(), type_s.to_string());
+        let schema = match self.schemas.get(&key) {
+            Some(schema) => schema,
+            None => {
+                return Err(vec![format!(
+                    "unknown event slug {:?} with type {:?}",
+                    slug, type_s
+                )])
+            }
+        };
+
+        let mut errors = Vec::new();
+        if let Some(metadata_schema) = &schema.metadata {
+            validate_value(metadata_schema, metadata, "metadata", &mut errors);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Checks a value against the commonly used part of JSON Schema: type, enum,
+// const, properties, required, additionalProperties, items, and the
+// min/max keywords. Anything else in the schema is ignored.
+fn validate_value(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            errors.push(format!("{} is not allowed", path));
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+
+    if let Some(types) = schema.get("type") {
+        let allowed: Vec<&str> = match types {
+            Value::String(type_s) => vec![type_s.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|type_s| is_type(value, type_s)) {
+            errors.push(format!("{} should be {}", path, allowed.join(" or ")));
+            // The rest of the keywords won't make sense for the wrong type
+            return;
+        }
+    }
+
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            errors.push(format!(
+                "{} should be one of {}",
+                path,
+                Value::from(options.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            errors.push(format!("{} should be {}", path, expected));
+        }
+    }
+
+    let number = |name| schema.get(name).and_then(Value::as_f64);
+    let count = |name| schema.get(name).and_then(Value::as_u64).map(|n| n as usize);
+
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for key in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(key) {
+                        errors.push(format!("{}.{} is required", path, key));
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (key, item) in object {
+                let item_path = format!("{}.{}", path, key);
+                match properties.and_then(|properties| properties.get(key)) {
+                    Some(item_schema) => validate_value(item_schema, item, &item_path, errors),
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            validate_value(additional, item, &item_path, errors);
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min) = count("minItems") {
+                if items.len() < min {
+                    errors.push(format!("{} should have at least {} items", path, min));
+                }
+            }
+            if let Some(max) = count("maxItems") {
+                if items.len() > max {
+                    errors.push(format!("{} should have at most {} items", path, max));
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_value(item_schema, item, &format!("{}[{}]", path, i), errors);
+                }
+            }
+        }
+        Value::String(s) => {
+            let length = s.chars().count();
+            if let Some(min) = count("minLength") {
+                if length < min {
+                    errors.push(format!("{} should be at least {} characters", path, min));
+                }
+            }
+            if let Some(max) = count("maxLength") {
+                if length > max {
+                    errors.push(format!("{} should be at most {} characters", path, max));
+                }
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap();
+            if let Some(min) = number("minimum") {
+                if n < min {
+                    errors.push(format!("{} should be at least {}", path, min));
+                }
+            }
+            if let Some(max) = number("maximum") {
+                if n > max {
+                    errors.push(format!("{} should be at most {}", path, max));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_type(value: &Value, type_s: &str) -> bool {
+    match type_s {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use serde_json::json;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.register(EventSchema {
+            slug: "build".to_string(),
+            type_s: "command".to_string(),
+            metadata: Some(json!({
+                "type": "object",
+                "required": ["duration"],
+                "properties": {
+                    "duration": {"type": "number", "minimum": 0},
+                    "target": {"enum": ["debug", "release"]},
+                    "flags": {"type": "array", "items": {"type": "string", "maxLength": 5}},
+                },
+                "additionalProperties": false,
+            })),
+        });
+        registry.register(EventSchema {
+            slug: "anything".to_string(),
+            type_s: "command".to_string(),
+            metadata: None,
+        });
+        registry
+    }
+
+    #[test]
+    fn empty_registry_allows_everything() {
+        assert!(Registry::new()
+            .validate("build", "command", &json!(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn validate() {
+        let registry = registry();
+        assert!(registry
+            .validate(
+                "build",
+                "command",
+                &json!({"duration": 1.5, "flags": ["-v"]})
+            )
+            .is_ok());
+        assert!(registry
+            .validate("anything", "command", &json!([1, 2]))
+            .is_ok());
+
+        assert_eq!(
+            registry.validate("deploy", "command", &json!({})),
+            Err(vec![
+                "unknown event slug \"deploy\" with type \"command\"".to_string()
+            ])
+        );
+        assert!(registry
+            .validate("build", "error", &json!({"duration": 1}))
+            .is_err());
+
+        let errors = registry
+            .validate(
+                "build",
+                "command",
+                &json!({"target": "fast", "flags": ["--verbose", 1], "extra": true}),
+            )
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "metadata.duration is required",
+                "metadata.extra is not allowed",
+                "metadata.flags[0] should be at most 5 characters",
+                "metadata.flags[1] should be string",
+                "metadata.target should be one of [\"debug\",\"release\"]",
+            ]
+        );
+    }
+
+    #[test]
+    fn load_catalog() {
+        let dir = TempDir::new();
+        let path = dir.join("catalog.json");
+        fs::write(
+            &path,
+            r#"[{"slug": "build", "type": "command", "metadata": {"type": "object"}}]"#,
+        )
+        .unwrap();
+
+        let mut registry = Registry::new();
+        registry.load_catalog(&path).unwrap();
+        assert!(registry.validate("build", "command", &json!({})).is_ok());
+        assert!(registry.validate("build", "command", &json!([])).is_err());
+
+        fs::write(&path, "{").unwrap();
+        assert!(Registry::new().load_catalog(&path).is_err());
+    }
+}
@@ -1,13 +1,28 @@
-use super::events;
+use super::api::{ApiError, ClientOptions};
+use super::ci;
+use super::consent::{self, Category, PromptMode};
+use super::context;
+use super::events::{self, LogLimits};
+use super::redact::Redactor;
+use super::retry::RetryPolicy;
+use super::schema::{self, EventSchema};
+use super::store::{self, EventStore, StoreKind};
+use super::template;
+use super::transport::TransportConfig;
+use super::truncate::MetadataLimits;
 use atty::Stream;
 use colored::*;
 use ctrlc;
-use dialoguer::Confirm;
+use dialoguer::{Confirm, Select};
 use dirs;
+use once_cell::unsync::OnceCell;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::fs;
 use std::path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use uuid::Uuid;
 
 const DEFAULT_REQUEST_PROMPT: &str = r#"
@@ -19,7 +34,11 @@ Here's an example of an event we would collect:
 {event_data}
 
 Your settings will be saved here and can be changed at any time:
-{settings_path}
+{settings_path}{#opt_out_command}
+To stop tracking, run: {opt_out_command}{/opt_out_command}{#privacy_policy_url}
+
+Read more about what we collect in our privacy policy:
+{privacy_policy_url}{/privacy_policy_url}
 
 Can we collect anonymous usage data from your installation?
 "#;
@@ -28,24 +47,80 @@ const DEFAULT_ERROR_PROMPT: &str = r#"
 Uh oh, there was an error! Reporting these issues back to us helps improve our tools.
 
 Here's the data we would collect:
-{event_data}
+{event_data}{#privacy_policy_url}
+
+Read more about what we collect in our privacy policy:
+{privacy_policy_url}{/privacy_policy_url}
 
 Do you want to anonymously report this?
 "#;
 
+// Shown before asking about each consent category when the prompt mode is "separate"
+const DEFAULT_CATEGORIES_PROMPT: &str = r#"
+To help improve the quality of our tools, we'd like to collect some
+anonymized information. You can choose which kinds below.
+
+Here's an example of an event we would collect:
+{event_data}
+
+Your settings will be saved here and can be changed at any time:
+{settings_path}{#opt_out_command}
+To stop tracking, run: {opt_out_command}{/opt_out_command}{#privacy_policy_url}
+
+Read more about what we collect in our privacy policy:
+{privacy_policy_url}{/privacy_policy_url}
+"#;
+
+// Answers to the error prompt. "Always" and "never" are saved as the
+// errors consent, so the prompt isn't shown again.
+const ERROR_PROMPT_CHOICES: &[&str] = &["Send this time", "Always send", "Never send", "Not now"];
+
+// Shown above the request prompt when asking again because the policy changed
+const POLICY_CHANGED_PROMPT: &str = r#"
+What we collect has changed since you last agreed:
+{policy_changes}
+"#;
+
+// Long enough for a slow network, short enough that a hung
+// connection doesn't noticeably stall the host command
+const DEFAULT_API_TIMEOUT: u64 = 10; // seconds
+
 #[derive(Debug)]
 pub struct Settings {
     pub project_slug: String,
     pub instance_id: String, // Identifies an exact instance of CLS (could have a package using CLS installed multiple times on the same machine)
     pub request_permission_prompt: String,
     pub error_prompt: String,
+    pub categories_prompt: String,
     pub ci_tracking_enabled: bool,
     pub version: String,
     _is_ci: Option<bool>,
     _project_key: String,
-    _debug: bool,
     _user_id: String,
     _invocation_id: String,
+    _retry_policy: RetryPolicy,
+    _gzip: bool,
+    _api_timeout: u64,
+    _proxy: String,
+    _ca_bundle: String,
+    _transport: String,
+    _log_limits: LogLimits,
+    _metadata_limits: MetadataLimits,
+    _redaction_patterns: Vec<String>,
+    _redactor: OnceCell<Redactor>,
+    _context: String,
+    _ci_tracking_providers: String,
+    _policy_version: u32,
+    _policy_changes: String,
+    _event_categories: HashMap<String, Category>,
+    _consent_categories: String,
+    _prompt_mode: String,
+    _privacy_policy_url: String,
+    _opt_out_command: String,
+    _event_store: String,
+    _event_schemas: schema::Registry,
+    _event_catalog: String,
+    _catalog_schemas: OnceCell<schema::Registry>,
 }
 
 // should probably be configurable too
@@ -63,6 +138,28 @@ fn get_env_setting(name: &str) -> Option<String> {
     None
 }
 
+// Kept out of Settings so debug_print can be called from the sender
+// thread, while set_* calls change SETTINGS on the caller's thread
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn get_debug() -> bool {
+    get_env_bool("DEBUG").unwrap_or(DEBUG.load(Ordering::Relaxed))
+}
+
+// For settings where 0 means "use the default"
+fn nonzero_or<T: Default + PartialEq>(val: T, default: T) -> T {
+    if val == T::default() {
+        default
+    } else {
+        val
+    }
+}
+
+// Boolean env settings are on unless set to "false" or "0"
+fn get_env_bool(name: &str) -> Option<bool> {
+    get_env_setting(name).map(|val| val != "false" && val != "0")
+}
+
 impl Settings {
     pub fn new() -> Settings {
         Settings {
@@ -70,13 +167,39 @@ impl Settings {
             instance_id: String::from(""),
             request_permission_prompt: String::from(DEFAULT_REQUEST_PROMPT),
             error_prompt: String::from(DEFAULT_ERROR_PROMPT),
+            categories_prompt: String::from(DEFAULT_CATEGORIES_PROMPT),
             ci_tracking_enabled: false,
             version: String::from(""),
             _is_ci: None, // defaults to CI env var unless explicitly set
             _project_key: String::from(""),
-            _debug: false,
             _user_id: String::from(""),
             _invocation_id: String::from(""),
+            _retry_policy: RetryPolicy::new(),
+            _gzip: true,
+            _api_timeout: DEFAULT_API_TIMEOUT,
+            _proxy: String::from(""),
+            _ca_bundle: String::from(""),
+            _transport: String::from("http"),
+            _log_limits: LogLimits {
+                max_bytes: events::DEFAULT_MAX_LOG_BYTES,
+                max_events: events::DEFAULT_MAX_LOG_EVENTS,
+            },
+            _metadata_limits: MetadataLimits::default(),
+            _redaction_patterns: Vec::new(),
+            _redactor: OnceCell::new(),
+            _context: String::from("all"),
+            _ci_tracking_providers: String::from(""),
+            _policy_version: 0,
+            _policy_changes: String::from(""),
+            _event_categories: HashMap::new(),
+            _consent_categories: String::from("usage,performance"),
+            _prompt_mode: String::from("together"),
+            _privacy_policy_url: String::from(""),
+            _opt_out_command: String::from(""),
+            _event_store: String::from("file"),
+            _event_schemas: schema::Registry::new(),
+            _event_catalog: String::from(""),
+            _catalog_schemas: OnceCell::new(),
         }
     }
 
@@ -89,15 +212,75 @@ impl Settings {
     }
 
     pub fn set_debug(&mut self, debug: bool) {
-        self._debug = debug;
+        DEBUG.store(debug, Ordering::Relaxed);
     }
 
     pub fn get_debug(&self) -> bool {
-        let env_val = get_env_setting("DEBUG").unwrap_or("".to_string());
-        if env_val.len() > 0 {
-            return env_val != "false" && env_val != "0";
+        get_debug()
+    }
+
+    pub fn set_gzip(&mut self, gzip: bool) {
+        self._gzip = gzip;
+    }
+
+    pub fn get_gzip(&self) -> bool {
+        get_env_bool("GZIP").unwrap_or(self._gzip)
+    }
+
+    // 0 would make every request time out, so it means the default
+    pub fn set_api_timeout(&mut self, seconds: u64) {
+        self._api_timeout = if seconds > 0 {
+            seconds
+        } else {
+            DEFAULT_API_TIMEOUT
+        };
+    }
+
+    pub fn get_api_timeout(&self) -> u64 {
+        get_env_setting("API_TIMEOUT")
+            .and_then(|val| val.parse().ok())
+            .filter(|seconds| *seconds > 0)
+            .unwrap_or(self._api_timeout)
+    }
+
+    pub fn set_proxy(&mut self, url: &str) {
+        self._proxy = url.to_string();
+    }
+
+    pub fn get_proxy(&self) -> String {
+        get_env_setting("PROXY").unwrap_or(self._proxy.clone())
+    }
+
+    pub fn set_ca_bundle(&mut self, path: &str) {
+        self._ca_bundle = path.to_string();
+    }
+
+    pub fn get_ca_bundle(&self) -> String {
+        get_env_setting("CA_BUNDLE").unwrap_or(self._ca_bundle.clone())
+    }
+
+    pub fn get_client_options(&self) -> ClientOptions {
+        let non_empty = |val: String| if val.is_empty() { None } else { Some(val) };
+
+        ClientOptions {
+            token: self.get_project_key(),
+            gzip: self.get_gzip(),
+            timeout: Duration::from_secs(self.get_api_timeout()),
+            proxy: non_empty(self.get_proxy()),
+            ca_bundle: non_empty(self.get_ca_bundle()),
         }
-        return self._debug;
+    }
+
+    pub fn set_transport(&mut self, spec: &str) {
+        self._transport = spec.to_string();
+    }
+
+    pub fn get_transport(&self) -> String {
+        get_env_setting("TRANSPORT").unwrap_or(self._transport.clone())
+    }
+
+    pub fn get_transport_config(&self) -> Result<TransportConfig, ApiError> {
+        TransportConfig::parse(&self.get_transport(), self.get_client_options())
     }
 
     pub fn set_user_id(&mut self, user_id: &str) {
@@ -137,6 +320,248 @@ impl Settings {
         Uuid::new_v4().to_string()
     }
 
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self._retry_policy = policy;
+    }
+
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        let env_u64 = |name| get_env_setting(name).and_then(|val| val.parse::<u64>().ok());
+
+        RetryPolicy {
+            max_attempts: env_u64("MAX_ATTEMPTS")
+                .map(|val| val as u32)
+                .unwrap_or(self._retry_policy.max_attempts),
+            base_delay: env_u64("RETRY_BASE_DELAY").unwrap_or(self._retry_policy.base_delay),
+            max_delay: env_u64("RETRY_MAX_DELAY").unwrap_or(self._retry_policy.max_delay),
+        }
+    }
+
+    // A limit of 0 would drop every event as soon as it's logged,
+    // so like an API timeout of 0 it means the default
+    pub fn set_log_limits(&mut self, limits: LogLimits) {
+        self._log_limits = LogLimits {
+            max_bytes: nonzero_or(limits.max_bytes, events::DEFAULT_MAX_LOG_BYTES),
+            max_events: nonzero_or(limits.max_events, events::DEFAULT_MAX_LOG_EVENTS),
+        };
+    }
+
+    pub fn get_log_limits(&self) -> LogLimits {
+        LogLimits {
+            max_bytes: get_env_setting("MAX_LOG_BYTES")
+                .and_then(|val| val.parse().ok())
+                .filter(|max_bytes| *max_bytes > 0)
+                .unwrap_or(self._log_limits.max_bytes),
+            max_events: get_env_setting("MAX_LOG_EVENTS")
+                .and_then(|val| val.parse().ok())
+                .filter(|max_events| *max_events > 0)
+                .unwrap_or(self._log_limits.max_events),
+        }
+    }
+
+    // Limits of 0 mean the default too, rather than emptying every event's metadata
+    pub fn set_metadata_limits(&mut self, limits: MetadataLimits) {
+        let default = MetadataLimits::default();
+        self._metadata_limits = MetadataLimits {
+            max_bytes: nonzero_or(limits.max_bytes, default.max_bytes),
+            max_string_bytes: nonzero_or(limits.max_string_bytes, default.max_string_bytes),
+            max_array_items: nonzero_or(limits.max_array_items, default.max_array_items),
+            max_depth: nonzero_or(limits.max_depth, default.max_depth),
+        };
+    }
+
+    pub fn get_metadata_limits(&self) -> MetadataLimits {
+        let env_usize = |name| {
+            get_env_setting(name)
+                .and_then(|val| val.parse::<usize>().ok())
+                .filter(|limit| *limit > 0)
+        };
+
+        MetadataLimits {
+            max_bytes: env_usize("MAX_METADATA_BYTES").unwrap_or(self._metadata_limits.max_bytes),
+            max_string_bytes: env_usize("MAX_STRING_BYTES")
+                .unwrap_or(self._metadata_limits.max_string_bytes),
+            max_array_items: env_usize("MAX_ARRAY_ITEMS")
+                .unwrap_or(self._metadata_limits.max_array_items),
+            max_depth: env_usize("MAX_METADATA_DEPTH").unwrap_or(self._metadata_limits.max_depth),
+        }
+    }
+
+    pub fn add_redaction_pattern(&mut self, pattern: &str) -> Result<(), String> {
+        Redactor::check_pattern(pattern)?;
+        self._redaction_patterns.push(pattern.to_string());
+        self._redactor = OnceCell::new();
+        Ok(())
+    }
+
+    pub fn get_redaction_patterns(&self) -> Vec<String> {
+        self._redaction_patterns.clone()
+    }
+
+    // Compiled the first time it's needed, and again after a pattern is added
+    pub fn get_redactor(&self) -> &Redactor {
+        self._redactor.get_or_init(|| {
+            let home = dirs::home_dir().map(|home| home.to_string_lossy().into_owned());
+            let username = env::var("USER").or_else(|_| env::var("USERNAME")).ok();
+            Redactor::new(
+                home.as_deref(),
+                username.as_deref(),
+                &self._redaction_patterns,
+            )
+        })
+    }
+
+    pub fn set_context(&mut self, items: &str) {
+        self._context = items.to_string();
+    }
+
+    pub fn get_context(&self) -> String {
+        match env::var(format!("{}_CONTEXT", CLS_ENV_PREFIX)) {
+            // Can be set to "" to turn the context off
+            Ok(items) => items,
+            Err(_) => self._context.clone(),
+        }
+    }
+
+    pub fn collect_context(&self) -> Option<context::Context> {
+        context::Context::collect(&self.get_context_items())
+    }
+
+    pub fn get_context_items(&self) -> Vec<String> {
+        context::parse_items(&self.get_context()).unwrap_or_else(|err| {
+            super::debug_print(format!("{}, leaving out the context", err));
+            Vec::new()
+        })
+    }
+
+    pub fn set_event_store(&mut self, spec: &str) {
+        self._event_store = spec.to_string();
+    }
+
+    pub fn get_event_store(&self) -> String {
+        get_env_setting("EVENT_STORE").unwrap_or(self._event_store.clone())
+    }
+
+    fn get_event_store_kind(&self) -> StoreKind {
+        StoreKind::parse(&self.get_event_store()).unwrap_or_else(|err| {
+            super::debug_print(format!("{}, using events.log", err));
+            StoreKind::File
+        })
+    }
+
+    pub fn register_event_schema(&mut self, schema: EventSchema) {
+        self._event_schemas.register(schema);
+    }
+
+    // Loaded right away, so it isn't read again for every event
+    pub fn set_event_catalog(&mut self, path: &str) {
+        self._event_catalog = path.to_string();
+        self._catalog_schemas = OnceCell::new();
+        self.get_catalog_schemas();
+    }
+
+    pub fn get_event_catalog(&self) -> String {
+        get_env_setting("EVENT_CATALOG").unwrap_or(self._event_catalog.clone())
+    }
+
+    // The catalog file's schemas, loaded the first time they're needed
+    // (when it's set with CLS_EVENT_CATALOG)
+    fn get_catalog_schemas(&self) -> &schema::Registry {
+        self._catalog_schemas.get_or_init(|| {
+            let mut schemas = schema::Registry::new();
+            let catalog = self.get_event_catalog();
+            if !catalog.is_empty() {
+                if let Err(err) = schemas.load_catalog(path::Path::new(&catalog)) {
+                    super::debug_print(err);
+                }
+            }
+            schemas
+        })
+    }
+
+    // Schemas from the catalog file take precedence over registered ones
+    pub fn validate_event(
+        &self,
+        slug: &str,
+        type_s: &str,
+        metadata: &serde_json::Value,
+    ) -> Result<(), Vec<String>> {
+        let catalog = self.get_catalog_schemas();
+        if catalog.contains(slug, type_s) || self._event_schemas.is_empty() {
+            return catalog.validate(slug, type_s, metadata);
+        }
+        self._event_schemas.validate(slug, type_s, metadata)
+    }
+
+    // Bumped by the project whenever what it collects changes, along with a
+    // description of the changes for the users who agreed to an older version
+    pub fn set_consent_policy(&mut self, version: u32, changes: &str) {
+        self._policy_version = version;
+        self._policy_changes = changes.to_string();
+    }
+
+    pub fn get_policy_version(&self) -> u32 {
+        get_env_setting("POLICY_VERSION")
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(self._policy_version)
+    }
+
+    pub fn get_policy_changes(&self) -> String {
+        self._policy_changes.clone()
+    }
+
+    pub fn set_event_category(&mut self, type_s: &str, category: Category) {
+        self._event_categories.insert(type_s.to_string(), category);
+    }
+
+    pub fn get_event_category(&self, type_s: &str) -> Category {
+        match self._event_categories.get(type_s) {
+            Some(category) => *category,
+            None => Category::default_for_type(type_s),
+        }
+    }
+
+    // Categories asked about up front. Errors aren't by default,
+    // so the user is asked about each one as it happens instead.
+    pub fn set_consent_categories(&mut self, spec: &str) {
+        self._consent_categories = spec.to_string();
+    }
+
+    pub fn get_consent_categories(&self) -> String {
+        get_env_setting("CONSENT_CATEGORIES").unwrap_or(self._consent_categories.clone())
+    }
+
+    fn get_consent_category_list(&self) -> Vec<Category> {
+        consent::parse_categories(&self.get_consent_categories()).unwrap_or_else(|err| {
+            super::debug_print(format!("{}, asking about usage only", err));
+            vec![Category::Usage]
+        })
+    }
+
+    pub fn set_prompt_mode(&mut self, spec: &str) {
+        self._prompt_mode = spec.to_string();
+    }
+
+    pub fn get_prompt_mode(&self) -> String {
+        get_env_setting("PROMPT_MODE").unwrap_or(self._prompt_mode.clone())
+    }
+
+    pub fn set_privacy_policy_url(&mut self, url: &str) {
+        self._privacy_policy_url = url.to_string();
+    }
+
+    pub fn get_privacy_policy_url(&self) -> String {
+        get_env_setting("PRIVACY_POLICY_URL").unwrap_or(self._privacy_policy_url.clone())
+    }
+
+    // Like "mytool telemetry off", for the prompts to mention
+    pub fn set_opt_out_command(&mut self, command: &str) {
+        self._opt_out_command = command.to_string();
+    }
+
+    pub fn get_opt_out_command(&self) -> String {
+        get_env_setting("OPT_OUT_COMMAND").unwrap_or(self._opt_out_command.clone())
+    }
+
     pub fn set_is_ci(&mut self, is_ci: bool) {
         self._is_ci = Some(is_ci);
     }
@@ -150,7 +575,39 @@ impl Settings {
             return true;
         }
 
-        return false;
+        // Jenkins and Azure Pipelines don't set CI
+        return ci::detect_provider().is_some();
+    }
+
+    // None if not in CI, or it was turned off with set_is_ci
+    pub fn get_ci_provider(&self) -> Option<String> {
+        if self._is_ci == Some(false) {
+            return None;
+        }
+        ci::detect_provider()
+    }
+
+    pub fn set_ci_tracking_providers(&mut self, spec: &str) {
+        self._ci_tracking_providers = spec.to_string();
+    }
+
+    pub fn get_ci_tracking_providers(&self) -> String {
+        get_env_setting("CI_TRACKING_PROVIDERS").unwrap_or(self._ci_tracking_providers.clone())
+    }
+
+    // ci_tracking_enabled, unless there's a rule for this provider
+    fn should_track_ci(&self) -> bool {
+        let rules =
+            ci::parse_provider_rules(&self.get_ci_tracking_providers()).unwrap_or_else(|err| {
+                super::debug_print(format!("{}, ignoring the CI provider rules", err));
+                Vec::new()
+            });
+        let provider = self.get_ci_provider();
+        rules
+            .iter()
+            .find(|(name, _)| Some(name) == provider.as_ref())
+            .map(|(_, allowed)| *allowed)
+            .unwrap_or(self.ci_tracking_enabled)
     }
 
     // Returns a <slug>_cls_<instance_id> string
@@ -177,6 +634,22 @@ impl Settings {
         cache_dir
     }
 
+    pub fn get_event_log(&self) -> Box<dyn EventStore> {
+        store::open(
+            self.get_event_store_kind(),
+            &self.get_cache_dir(),
+            &self.get_log_limits(),
+        )
+    }
+
+    pub fn get_dead_letter_log(&self) -> Box<dyn EventStore> {
+        store::open_dead_letter(
+            self.get_event_store_kind(),
+            &self.get_cache_dir(),
+            &self.get_log_limits(),
+        )
+    }
+
     fn get_user_settings_path(&self) -> path::PathBuf {
         let mut settings_path = self.get_config_dir();
         settings_path.push("settings.json");
@@ -189,23 +662,50 @@ impl Settings {
             return serde_json::Value::default();
         }
         let mut settings_file = fs::File::open(settings_path).unwrap();
-        let settings = serde_json::from_reader(&mut settings_file).unwrap();
+        let mut settings = serde_json::from_reader(&mut settings_file).unwrap();
+        if migrate_legacy_consent(&mut settings) {
+            self.write_user_settings(&settings);
+        }
         return settings;
     }
 
     fn set_user_setting(&self, key: &str, value: &serde_json::Value) {
         let mut settings = self.get_user_settings();
         settings[key] = value.clone();
+        self.write_user_settings(&settings);
+    }
+
+    fn write_user_settings(&self, settings: &serde_json::Value) {
         let settings_path = self.get_user_settings_path();
         let settings_dir = settings_path.parent().unwrap();
         if !settings_dir.exists() {
             fs::create_dir_all(settings_dir).unwrap();
         }
         let mut settings_file = fs::File::create(settings_path).unwrap();
-        serde_json::to_writer_pretty(&mut settings_file, &settings).unwrap();
+        serde_json::to_writer_pretty(&mut settings_file, settings).unwrap();
+    }
+
+    // Why the user has opted out of tracking for every tool, if they have
+    pub fn get_global_opt_out(&self) -> Option<String> {
+        global_opt_out()
+    }
+
+    // Categories stored events can no longer be sent for, because the user
+    // has since turned them off. Every category if they've opted out globally.
+    pub fn get_declined_categories(&self) -> Vec<Category> {
+        if self.get_global_opt_out().is_some() {
+            return consent::CATEGORIES.to_vec();
+        }
+        declined_categories(&self.get_user_settings(), self.get_policy_version())
     }
 
     pub fn should_track_event(&self, event: &events::Event) -> Result<bool, Box<dyn Error>> {
+        // Before anything is prompted for or saved
+        if let Some(reason) = self.get_global_opt_out() {
+            super::debug_print(format!("Not tracking, {}", reason));
+            return Ok(false);
+        }
+
         let user_settings = self.get_user_settings();
 
         if user_settings.get("user_id").is_none() {
@@ -217,16 +717,27 @@ impl Settings {
         }
 
         if self.get_is_ci() {
-            return Ok(self.ci_tracking_enabled);
+            return Ok(self.should_track_ci());
         }
 
-        if event.type_s == "error" {
-            return self.should_track_error(event);
+        let category = self.get_event_category(&event.type_s);
+        let policy_version = self.get_policy_version();
+        if let Some(allowed) = get_saved_consent(&user_settings, category, policy_version) {
+            return Ok(allowed);
         }
 
-        let already_enabled = user_settings.get("tracking_enabled");
-        if !already_enabled.is_none() {
-            return Ok(already_enabled.unwrap().as_bool().unwrap());
+        // Decisions that still apply aren't asked about, or overwritten, again
+        let categories = undecided_categories(
+            &user_settings,
+            &self.get_consent_category_list(),
+            policy_version,
+        );
+        if !categories.contains(&category) {
+            if category == Category::Errors {
+                return self.should_track_error(event);
+            }
+            // The project doesn't ask about this category
+            return Ok(false);
         }
 
         if !atty::is(Stream::Stdin) {
@@ -234,29 +745,157 @@ impl Settings {
             return Ok(false);
         }
 
-        let prompt = self.request_permission_prompt.trim();
-        let prompt = prompt.replace(
-            "{event_data}",
-            &serde_json::to_string_pretty(event).unwrap(),
-        );
-        let prompt = prompt.replace(
-            "{settings_path}",
-            &self.get_user_settings_path().to_str().unwrap(),
-        );
+        let decisions = self.prompt_for_consent(event, &user_settings, &categories)?;
+        self.save_consent(&decisions, policy_version);
+        Ok(decisions
+            .iter()
+            .any(|(decided, allowed)| *decided == category && *allowed))
+    }
+
+    fn prompt_for_consent(
+        &self,
+        event: &events::Event,
+        user_settings: &serde_json::Value,
+        categories: &[Category],
+    ) -> Result<Vec<(Category, bool)>, Box<dyn Error>> {
+        let mode = PromptMode::parse(&self.get_prompt_mode()).unwrap_or_else(|err| {
+            super::debug_print(format!("{}, asking about every category together", err));
+            PromptMode::Together
+        });
+
+        let mut prompt = match mode {
+            PromptMode::Together => self.request_permission_prompt.trim().to_string(),
+            PromptMode::Separate => self.categories_prompt.trim().to_string(),
+        };
+        // Only asking again because they agreed to an older policy
+        let decided = user_settings.get("consent").is_some();
+        if decided && !self.get_policy_changes().is_empty() {
+            prompt = POLICY_CHANGED_PROMPT.trim().to_string() + "\n\n" + &prompt;
+        }
+        let prompt = self.render_prompt(&prompt, event);
 
-        ctrlc::set_handler(move || {
-            // Put the cursor back if ctrl c
-            let term = dialoguer::console::Term::stdout();
-            term.show_cursor().unwrap();
-        })?;
+        restore_cursor_on_interrupt();
 
-        let tracking_enabled = Confirm::new().with_prompt(prompt).interact()?;
+        match mode {
+            PromptMode::Together => {
+                let allowed = Confirm::new().with_prompt(prompt).interact()?;
+                Ok(categories
+                    .iter()
+                    .map(|category| (*category, allowed))
+                    .collect())
+            }
+            PromptMode::Separate => {
+                // Where dialoguer asks the questions, so it stays out of a piped stdout
+                let term = dialoguer::console::Term::stderr();
+                term.write_line(&prompt)?;
+                term.write_line("")?;
+                let mut decisions = Vec::new();
+                for category in categories {
+                    let allowed = Confirm::new().with_prompt(category.question()).interact()?;
+                    decisions.push((*category, allowed));
+                }
+                Ok(decisions)
+            }
+        }
+    }
+
+    fn save_consent(&self, decisions: &[(Category, bool)], policy_version: u32) {
+        let mut user_settings = self.get_user_settings();
+        record_consent(&mut user_settings, decisions, policy_version);
+        self.write_user_settings(&user_settings);
+    }
+
+    // For "telemetry on/off" commands in the host CLI, saved the same
+    // way as answering the prompt for every category
+    pub fn set_tracking_enabled(&self, enabled: bool) {
+        let decisions: Vec<(Category, bool)> = consent::CATEGORIES
+            .iter()
+            .map(|category| (*category, enabled))
+            .collect();
+        self.save_consent(&decisions, self.get_policy_version());
+    }
+
+    pub fn set_category_enabled(&self, category: Category, enabled: bool) {
+        self.save_consent(&[(category, enabled)], self.get_policy_version());
+    }
+
+    // Forgets every decision, so the user is prompted again
+    pub fn reset_consent(&self) {
+        if !self.get_user_settings_path().exists() {
+            return;
+        }
+        let mut settings = self.get_user_settings();
+        if let Some(settings) = settings.as_object_mut() {
+            for key in &["consent", "tracking_enabled", "policy_version"] {
+                settings.remove(*key);
+            }
+        }
+        self.write_user_settings(&settings);
+    }
+
+    // For a "telemetry status" command. Categories are null when the user
+    // hasn't decided yet (or will be asked again), and policy_versions has
+    // the version each decision was made under.
+    pub fn get_tracking_status(&self) -> serde_json::Value {
+        let user_settings = self.get_user_settings();
+        let policy_version = self.get_policy_version();
+        let mut categories = serde_json::Map::new();
+        let mut policy_versions = serde_json::Map::new();
+        for category in consent::CATEGORIES {
+            let allowed = get_saved_consent(&user_settings, *category, policy_version);
+            categories.insert(
+                category.key().to_string(),
+                serde_json::to_value(allowed).unwrap(),
+            );
+            policy_versions.insert(
+                category.key().to_string(),
+                user_settings["consent"][category.key()]["policy_version"].clone(),
+            );
+        }
+
+        serde_json::json!({
+            "categories": categories,
+            "opted_out": self.get_global_opt_out(),
+            "ci": self.get_is_ci(),
+            "ci_provider": self.get_ci_provider(),
+            "ci_tracking_enabled": self.should_track_ci(),
+            "policy_versions": policy_versions,
+            "current_policy_version": policy_version,
+            "settings_path": self.get_user_settings_path(),
+        })
+    }
+
+    // Fills in the placeholders a prompt can use (see template::render).
+    // Mistakes in the template are only reported in debug mode, the prompt
+    // is still shown with them left in.
+    fn render_prompt(&self, prompt: &str, event: &events::Event) -> String {
+        // Shown as it would be sent, the context is only collected once it's allowed
+        let mut event = event.clone();
+        if event.context.is_none() {
+            event.context = self.collect_context();
+        }
 
-        self.set_user_setting(
-            "tracking_enabled",
-            &serde_json::to_value(tracking_enabled).unwrap(),
+        let mut values = HashMap::new();
+        values.insert("event_data", serde_json::to_string_pretty(&event).unwrap());
+        values.insert(
+            "settings_path",
+            self.get_user_settings_path().to_str().unwrap().to_string(),
         );
-        return Ok(tracking_enabled);
+        values.insert("project_slug", self.project_slug.clone());
+        values.insert("version", self.version.clone());
+        values.insert("instance_id", self.instance_id.clone());
+        values.insert("privacy_policy_url", self.get_privacy_policy_url());
+        values.insert("opt_out_command", self.get_opt_out_command());
+        values.insert(
+            "policy_changes",
+            self.get_policy_changes().trim().to_string(),
+        );
+
+        let (prompt, errors) = template::render(prompt, &values);
+        for err in errors {
+            super::debug_print(format!("Prompt template: {}", err));
+        }
+        prompt
     }
 
     fn should_track_error(&self, event: &events::Event) -> Result<bool, Box<dyn Error>> {
@@ -265,29 +904,192 @@ impl Settings {
             return Ok(false);
         }
 
-        let prompt = self.error_prompt.trim();
-        let prompt = prompt
-            .replace(
-                "{event_data}",
-                &serde_json::to_string_pretty(event).unwrap(),
-            )
+        let prompt = self
+            .render_prompt(self.error_prompt.trim(), event)
             .yellow()
             .to_string();
 
-        ctrlc::set_handler(move || {
-            // Put the cursor back if ctrl c
-            let term = dialoguer::console::Term::stdout();
-            term.show_cursor().unwrap();
-        })?;
+        restore_cursor_on_interrupt();
+
+        let choice = Select::new()
+            .with_prompt(prompt)
+            .items(ERROR_PROMPT_CHOICES)
+            // "Not now", so just pressing enter doesn't send anything
+            .default(3)
+            .interact()?;
+        let (track_error, save) = match choice {
+            0 => (true, false),
+            1 => (true, true),
+            2 => (false, true),
+            _ => (false, false),
+        };
+        if save {
+            self.save_consent(
+                &[(Category::Errors, track_error)],
+                self.get_policy_version(),
+            );
+        }
+        Ok(track_error)
+    }
+}
+
+// Put the cursor back if ctrl c is pressed during a prompt. Only one handler
+// can be set per process, so later prompts keep using the first one.
+fn restore_cursor_on_interrupt() {
+    let _ = ctrlc::set_handler(move || {
+        let term = dialoguer::console::Term::stdout();
+        term.show_cursor().unwrap();
+    });
+}
+
+// Saved under "consent" in settings.json, like
+// {"usage": {"allowed": true, "policy_version": 2}}, so deciding on one
+// category doesn't change which policy the others were agreed to under.
+// The usage decision is also saved as "tracking_enabled" for older versions of cls.
+fn record_consent(
+    user_settings: &mut serde_json::Value,
+    decisions: &[(Category, bool)],
+    policy_version: u32,
+) {
+    if !user_settings["consent"].is_object() {
+        user_settings["consent"] = serde_json::json!({});
+    }
+    for (category, allowed) in decisions {
+        user_settings["consent"][category.key()] = serde_json::json!({
+            "allowed": allowed,
+            "policy_version": policy_version,
+        });
+        if *category == Category::Usage {
+            user_settings["tracking_enabled"] = serde_json::Value::Bool(*allowed);
+        }
+    }
+}
+
+// Decisions from before there were categories ("tracking_enabled", with the
+// policy_version it was made under) covered every event but errors, so it's
+// moved under "consent" for usage and performance the first time
+// settings.json is read. Returns whether it was.
+fn migrate_legacy_consent(user_settings: &mut serde_json::Value) -> bool {
+    if user_settings.get("consent").is_some() {
+        return false;
+    }
+    let allowed = match user_settings
+        .get("tracking_enabled")
+        .and_then(|val| val.as_bool())
+    {
+        Some(allowed) => allowed,
+        None => return false,
+    };
+    let policy_version = user_settings
+        .get("policy_version")
+        .and_then(|version| version.as_u64())
+        .unwrap_or(0);
+    record_consent(
+        user_settings,
+        &[(Category::Usage, allowed), (Category::Performance, allowed)],
+        policy_version as u32,
+    );
+    if let Some(user_settings) = user_settings.as_object_mut() {
+        user_settings.remove("policy_version");
+    }
+    true
+}
+
+// The decision saved in settings.json for a category, if it still applies.
+// Agreeing to an older policy version doesn't count as agreeing to this one,
+// but declining does.
+fn get_saved_consent(
+    user_settings: &serde_json::Value,
+    category: Category,
+    policy_version: u32,
+) -> Option<bool> {
+    let saved = user_settings.get("consent")?.get(category.key())?;
+    let allowed = saved.get("allowed")?.as_bool()?;
+    let saved_version = saved
+        .get("policy_version")
+        .and_then(|version| version.as_u64())
+        .unwrap_or(0);
+    if allowed && saved_version < policy_version as u64 {
+        return None;
+    }
+    Some(allowed)
+}
+
+// Shared by every tool that uses cls, the same ~/.config/cls/global.json on
+// every platform so users only have one place to look
+fn get_global_settings_path() -> Option<path::PathBuf> {
+    let mut settings_path = dirs::home_dir()?;
+    settings_path.push(".config");
+    settings_path.push("cls");
+    settings_path.push("global.json");
+    Some(settings_path)
+}
+
+// Doesn't need SETTINGS, so the sender thread can check it before each event
+pub fn global_opt_out() -> Option<String> {
+    if is_do_not_track(env::var("DO_NOT_TRACK").ok()) {
+        return Some("DO_NOT_TRACK is set".to_string());
+    }
+
+    let global_settings_path = get_global_settings_path()?;
+    if read_global_opt_out(&global_settings_path) {
+        return Some(format!("opted out in {:?}", global_settings_path));
+    }
+
+    None
+}
+
+// The categories that need asking about, out of the ones given
+fn undecided_categories(
+    user_settings: &serde_json::Value,
+    categories: &[Category],
+    policy_version: u32,
+) -> Vec<Category> {
+    categories
+        .iter()
+        .filter(|category| get_saved_consent(user_settings, **category, policy_version).is_none())
+        .copied()
+        .collect()
+}
+
+// The categories the user has said no to in settings.json
+fn declined_categories(user_settings: &serde_json::Value, policy_version: u32) -> Vec<Category> {
+    consent::CATEGORIES
+        .iter()
+        .filter(|category| {
+            get_saved_consent(user_settings, **category, policy_version) == Some(false)
+        })
+        .copied()
+        .collect()
+}
 
-        let track_error = Confirm::new().with_prompt(prompt).interact()?;
-        return Ok(track_error);
+// https://consoledonottrack.com, any value but "0" or "false" opts out
+fn is_do_not_track(val: Option<String>) -> bool {
+    match val {
+        Some(val) => !val.is_empty() && val != "0" && val.to_lowercase() != "false",
+        None => false,
+    }
+}
+
+// global.json with "tracking_enabled": false. A file that can't be read
+// or parsed counts as opted out, since the user clearly meant to set something.
+fn read_global_opt_out(path: &path::Path) -> bool {
+    if !path.exists() {
+        return false;
+    }
+    let settings: Option<serde_json::Value> = fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok());
+    match settings {
+        Some(settings) => settings.get("tracking_enabled") == Some(&serde_json::Value::Bool(false)),
+        None => true,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempDir;
 
     #[test]
     fn instance_dirnames() {
@@ -304,4 +1106,253 @@ mod tests {
         settings.project_slug = "".to_string();
         assert_eq!(settings.get_instance_dirname(), "cls_hash");
     }
+
+    #[test]
+    fn api_timeout() {
+        let mut settings = Settings::new();
+        settings.set_api_timeout(30);
+        assert_eq!(settings._api_timeout, 30);
+        settings.set_api_timeout(0);
+        assert_eq!(settings._api_timeout, DEFAULT_API_TIMEOUT);
+    }
+
+    #[test]
+    fn limits() {
+        let mut settings = Settings::new();
+        settings.set_log_limits(LogLimits {
+            max_bytes: 0,
+            max_events: 5,
+        });
+        assert_eq!(
+            settings._log_limits.max_bytes,
+            events::DEFAULT_MAX_LOG_BYTES
+        );
+        assert_eq!(settings._log_limits.max_events, 5);
+
+        settings.set_metadata_limits(MetadataLimits {
+            max_bytes: 0,
+            max_string_bytes: 10,
+            max_array_items: 0,
+            max_depth: 2,
+        });
+        let default = MetadataLimits::default();
+        assert_eq!(settings._metadata_limits.max_bytes, default.max_bytes);
+        assert_eq!(settings._metadata_limits.max_string_bytes, 10);
+        assert_eq!(
+            settings._metadata_limits.max_array_items,
+            default.max_array_items
+        );
+        assert_eq!(settings._metadata_limits.max_depth, 2);
+    }
+
+    #[test]
+    fn redactor() {
+        let mut settings = Settings::new();
+        let redact =
+            |settings: &Settings| settings.get_redactor().redact(serde_json::json!("acme-42"));
+        assert_eq!(redact(&settings), serde_json::json!("acme-42"));
+        settings.add_redaction_pattern(r"acme-[0-9]+").unwrap();
+        assert_ne!(redact(&settings), serde_json::json!("acme-42"));
+        assert!(std::ptr::eq(
+            settings.get_redactor(),
+            settings.get_redactor()
+        ));
+    }
+
+    #[test]
+    fn event_catalog() {
+        let dir = TempDir::new();
+        let path = dir.join("catalog.json");
+        fs::write(
+            &path,
+            r#"[{"slug": "build", "type": "command", "metadata": {"type": "object"}}]"#,
+        )
+        .unwrap();
+
+        let mut settings = Settings::new();
+        assert!(settings
+            .validate_event("deploy", "command", &serde_json::json!(null))
+            .is_ok());
+        settings.register_event_schema(EventSchema {
+            slug: "build".to_string(),
+            type_s: "command".to_string(),
+            metadata: None,
+        });
+        settings.register_event_schema(EventSchema {
+            slug: "deploy".to_string(),
+            type_s: "command".to_string(),
+            metadata: None,
+        });
+        settings.set_event_catalog(path.to_str().unwrap());
+
+        // Only read when it's set
+        fs::remove_file(&path).unwrap();
+        let validate = |slug, metadata| settings.validate_event(slug, "command", &metadata);
+        assert!(validate("build", serde_json::json!({})).is_ok());
+        assert!(validate("build", serde_json::json!([])).is_err());
+        assert!(validate("deploy", serde_json::json!([])).is_ok());
+        assert!(validate("test", serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn saved_consent() {
+        let saved =
+            |json, category| get_saved_consent(&serde_json::from_str(json).unwrap(), category, 2);
+        assert_eq!(saved("{}", Category::Usage), None);
+
+        let settings = r#"{
+            "tracking_enabled": false,
+            "consent": {
+                "usage": {"allowed": false, "policy_version": 2},
+                "errors": {"allowed": true, "policy_version": 2}
+            }
+        }"#;
+        assert_eq!(saved(settings, Category::Usage), Some(false));
+        assert_eq!(saved(settings, Category::Errors), Some(true));
+        assert_eq!(saved(settings, Category::Performance), None);
+
+        let settings = r#"{"consent": {"usage": {"allowed": true, "policy_version": 1}}}"#;
+        assert_eq!(saved(settings, Category::Usage), None);
+    }
+
+    #[test]
+    fn legacy_consent() {
+        let migrated = |mut user_settings: serde_json::Value| {
+            assert!(migrate_legacy_consent(&mut user_settings));
+            // Only the first time
+            assert!(!migrate_legacy_consent(&mut user_settings));
+            user_settings
+        };
+
+        let user_settings = migrated(serde_json::json!({"tracking_enabled": true}));
+        assert_eq!(
+            get_saved_consent(&user_settings, Category::Usage, 0),
+            Some(true)
+        );
+        assert_eq!(
+            get_saved_consent(&user_settings, Category::Performance, 0),
+            Some(true)
+        );
+        assert_eq!(get_saved_consent(&user_settings, Category::Errors, 0), None);
+
+        let user_settings = migrated(serde_json::json!({
+            "tracking_enabled": true,
+            "policy_version": 2
+        }));
+        assert_eq!(user_settings.get("policy_version"), None);
+        assert_eq!(
+            get_saved_consent(&user_settings, Category::Usage, 2),
+            Some(true)
+        );
+        assert_eq!(get_saved_consent(&user_settings, Category::Usage, 3), None);
+
+        // Declining before still counts, and isn't asked about again
+        let user_settings = migrated(serde_json::json!({"tracking_enabled": false}));
+        assert_eq!(
+            get_saved_consent(&user_settings, Category::Usage, 3),
+            Some(false)
+        );
+        assert_eq!(
+            get_saved_consent(&user_settings, Category::Performance, 3),
+            Some(false)
+        );
+
+        assert!(!migrate_legacy_consent(&mut serde_json::json!({})));
+        assert!(!migrate_legacy_consent(&mut serde_json::json!(null)));
+    }
+
+    #[test]
+    fn undecided() {
+        let user_settings = serde_json::json!({
+            "consent": {
+                "usage": {"allowed": false, "policy_version": 1},
+                "performance": {"allowed": true, "policy_version": 1}
+            }
+        });
+        let categories = [Category::Usage, Category::Performance];
+        assert!(undecided_categories(&user_settings, &categories, 1).is_empty());
+        // Only agreeing is asked about again under a new policy
+        assert_eq!(
+            undecided_categories(&user_settings, &categories, 2),
+            vec![Category::Performance]
+        );
+        assert_eq!(
+            undecided_categories(&serde_json::json!({}), &categories, 1),
+            categories.to_vec()
+        );
+    }
+
+    #[test]
+    fn consent_versions() {
+        let mut user_settings = serde_json::json!(null);
+        record_consent(&mut user_settings, &[(Category::Usage, true)], 1);
+        assert_eq!(user_settings["tracking_enabled"], serde_json::json!(true));
+
+        // Answering the error prompt under a new policy doesn't count
+        // as agreeing to it for usage too
+        record_consent(&mut user_settings, &[(Category::Errors, true)], 2);
+        assert_eq!(
+            get_saved_consent(&user_settings, Category::Errors, 2),
+            Some(true)
+        );
+        assert_eq!(get_saved_consent(&user_settings, Category::Usage, 2), None);
+        assert_eq!(
+            get_saved_consent(&user_settings, Category::Usage, 1),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn event_categories() {
+        let mut settings = Settings::new();
+        assert_eq!(settings.get_event_category("command"), Category::Usage);
+        settings.set_event_category("timing", Category::Performance);
+        assert_eq!(settings.get_event_category("timing"), Category::Performance);
+    }
+
+    #[test]
+    fn do_not_track() {
+        assert!(!is_do_not_track(None));
+        assert!(!is_do_not_track(Some("".to_string())));
+        assert!(!is_do_not_track(Some("0".to_string())));
+        assert!(!is_do_not_track(Some("False".to_string())));
+        assert!(is_do_not_track(Some("1".to_string())));
+        assert!(is_do_not_track(Some("true".to_string())));
+    }
+
+    #[test]
+    fn declined() {
+        assert!(declined_categories(&serde_json::json!({}), 1).is_empty());
+        let user_settings = serde_json::json!({
+            "consent": {
+                "usage": {"allowed": true, "policy_version": 1},
+                "errors": {"allowed": false, "policy_version": 1}
+            }
+        });
+        assert_eq!(
+            declined_categories(&user_settings, 2),
+            vec![Category::Errors]
+        );
+    }
+
+    #[test]
+    fn global_settings_path() {
+        let path = get_global_settings_path().unwrap();
+        assert!(path.ends_with(".config/cls/global.json"));
+        assert!(path.starts_with(dirs::home_dir().unwrap()));
+    }
+
+    #[test]
+    fn global_opt_out() {
+        let dir = TempDir::new();
+        let path = dir.join("global.json");
+        assert!(!read_global_opt_out(&path));
+
+        fs::write(&path, r#"{"tracking_enabled": true}"#).unwrap();
+        assert!(!read_global_opt_out(&path));
+        fs::write(&path, r#"{"tracking_enabled": false}"#).unwrap();
+        assert!(read_global_opt_out(&path));
+        fs::write(&path, "{").unwrap();
+        assert!(read_global_opt_out(&path));
+    }
 }
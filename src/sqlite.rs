@@ -0,0 +1,431 @@
+use crate::events::{Event, LogLimits, StoredEvent};
+use crate::store::{Claim, EventStore};
+use chrono::{TimeZone, UTC};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use std::fs;
+use std::path;
+use std::time::Duration;
+use uuid::Uuid;
+
+// How long a process can hold claimed events before another dispatch
+// assumes it died and sends them again
+const CLAIM_LEASE: i64 = 60 * 60; // seconds
+
+// How long to wait on another process's write before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    claim_id TEXT,
+    claimed_at INTEGER,
+    error TEXT,
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_status ON events (status, created_at);
+CREATE INDEX IF NOT EXISTS events_attempts ON events (attempts);
+CREATE INDEX IF NOT EXISTS events_claim_id ON events (claim_id);
+CREATE TABLE IF NOT EXISTS counters (
+    name TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+";
+
+// Keeps events in an events.db next to where events.log would be. Every write
+// is a transaction, so any number of processes can record and dispatch at once.
+//
+// Rows move through these statuses:
+//   pending -> sending (claimed by a dispatch) -> deleted once sent, or pending again
+//   dead -> resending (only if the dead letters are ever claimed)
+//   corrupt, for rows that no longer parse as an Event
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    path: path::PathBuf,
+    limits: LogLimits,
+    status: &'static str,
+    claimed_status: &'static str,
+}
+
+impl SqliteStore {
+    pub fn new(dir: &path::Path, limits: &LogLimits) -> SqliteStore {
+        SqliteStore {
+            path: dir.join("events.db"),
+            limits: limits.clone(),
+            status: "pending",
+            claimed_status: "sending",
+        }
+    }
+
+    // Dead letters share the database, under their own statuses
+    pub fn dead_letter(dir: &path::Path, limits: &LogLimits) -> SqliteStore {
+        SqliteStore {
+            path: dir.join("events.db"),
+            limits: limits.clone(),
+            status: "dead",
+            claimed_status: "resending",
+        }
+    }
+
+    fn connect(&self) -> Connection {
+        fs::create_dir_all(self.path.parent().unwrap()).unwrap();
+        let conn = Connection::open(&self.path).unwrap();
+        conn.busy_timeout(BUSY_TIMEOUT).unwrap();
+        // Lets readers carry on while another process is writing
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+            .unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        conn
+    }
+
+    // Takes the write lock up front, so two processes can't both
+    // read and then fail to upgrade to a write
+    fn transaction<T>(&self, f: impl FnOnce(&Transaction) -> T) -> T {
+        let mut conn = self.connect();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .unwrap();
+        let result = f(&tx);
+        tx.commit().unwrap();
+        result
+    }
+
+    fn insert(&self, tx: &Transaction, event: &StoredEvent) {
+        tx.execute(
+            "INSERT INTO events (status, attempts, created_at, type, data)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                self.status,
+                event.attempts,
+                created_at(&event.event),
+                event.event.type_s,
+                serde_json::to_string(event).unwrap(),
+            ],
+        )
+        .unwrap();
+    }
+
+    fn dropped_counter(&self) -> String {
+        format!("dropped_{}", self.status)
+    }
+
+    // The limits apply to each status separately, so dead letters
+    // don't push out events that are still waiting to be sent
+    fn enforce_limits(&self, tx: &Transaction) {
+        let mut dropped = tx
+            .execute(
+                "DELETE FROM events WHERE id IN (
+                    SELECT id FROM events WHERE status = ?1
+                    ORDER BY created_at DESC, id DESC LIMIT -1 OFFSET ?2
+                )",
+                params![self.status, self.limits.max_events as i64],
+            )
+            .unwrap();
+        dropped += tx
+            .execute(
+                "DELETE FROM events WHERE id IN (
+                    SELECT id FROM (
+                        SELECT id, SUM(LENGTH(data) + 1)
+                            OVER (ORDER BY created_at DESC, id DESC) AS total
+                        FROM events WHERE status = ?1
+                    ) WHERE total > ?2
+                )",
+                params![self.status, self.limits.max_bytes as i64],
+            )
+            .unwrap();
+        if dropped == 0 {
+            return;
+        }
+
+        super::debug_print(format!(
+            "event store over its limits, dropping {:?} oldest events",
+            dropped
+        ));
+        tx.execute(
+            "INSERT INTO counters (name, value) VALUES (?1, ?2)
+            ON CONFLICT (name) DO UPDATE SET value = value + excluded.value",
+            params![self.dropped_counter(), dropped as i64],
+        )
+        .unwrap();
+    }
+}
+
+impl EventStore for SqliteStore {
+    fn record_event(&self, event: &StoredEvent) {
+        super::debug_print(format!(
+            "inserting_event_store path={:?} status={:?}",
+            self.path, self.status
+        ));
+        self.transaction(|tx| {
+            self.insert(tx, event);
+            self.enforce_limits(tx);
+        });
+    }
+
+    fn remove_event(&self, id: &str) -> bool {
+        self.transaction(|tx| {
+            let removed = tx
+                .execute(
+                    "DELETE FROM events WHERE status = ?1 AND json_extract(data, '$.id') = ?2",
+                    params![self.status, id],
+                )
+                .unwrap();
+            removed > 0
+        })
+    }
+
+    fn take_dropped(&self) -> u64 {
+        self.transaction(|tx| {
+            let dropped: Option<i64> = tx
+                .query_row(
+                    "DELETE FROM counters WHERE name = ?1 RETURNING value",
+                    params![self.dropped_counter()],
+                    |row| row.get(0),
+                )
+                .optional()
+                .unwrap();
+            dropped.unwrap_or(0) as u64
+        })
+    }
+
+    // Claims every event that isn't already being sent, so concurrent
+    // dispatches each send their own share instead of waiting on each other
+    fn claim_events(&self) -> Option<(Claim, Vec<StoredEvent>)> {
+        let claim_id = Uuid::new_v4().to_string();
+        let now = UTC::now().timestamp();
+
+        let events = self.transaction(|tx| {
+            // Claims that outlived their lease are from a dispatch
+            // that never finished (the process died)
+            tx.execute(
+                "UPDATE events SET status = ?1, claim_id = NULL, claimed_at = NULL
+                WHERE status = ?2 AND claimed_at < ?3",
+                params![self.status, self.claimed_status, now - CLAIM_LEASE],
+            )
+            .unwrap();
+            tx.execute(
+                "UPDATE events SET status = ?1, claim_id = ?2, claimed_at = ?3
+                WHERE status = ?4",
+                params![self.claimed_status, claim_id, now, self.status],
+            )
+            .unwrap();
+
+            let mut stmt = tx
+                .prepare("SELECT id, data FROM events WHERE claim_id = ?1 ORDER BY created_at, id")
+                .unwrap();
+            let rows: Vec<(i64, String)> = stmt
+                .query_map(params![claim_id], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .map(|row| row.unwrap())
+                .collect();
+
+            let mut events = Vec::new();
+            for (id, data) in rows {
+                match serde_json::from_str(&data) {
+                    Ok(event) => events.push(event),
+                    // Left in the table with the parse error
+                    Err(err) => {
+                        super::debug_print(format!("quarantining corrupt event id={:?}", id));
+                        tx.execute(
+                            "UPDATE events SET status = 'corrupt', error = ?1, claim_id = NULL
+                            WHERE id = ?2",
+                            params![err.to_string(), id],
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+            events
+        });
+
+        Some((Claim::Sqlite(claim_id), events))
+    }
+
+    // Marks the claimed events as sent (by deleting them) and puts back the
+    // ones that weren't, in one transaction so none are sent twice or lost
+    fn release_claim(&self, claim: Claim, remaining: &[StoredEvent]) {
+        let claim_id = match claim {
+            Claim::Sqlite(claim_id) => claim_id,
+            Claim::File { .. } => panic!("release_claim called with a file claim"),
+        };
+        super::debug_print(format!(
+            "releasing_event_store path={:?} remaining={:?}",
+            self.path,
+            remaining.len()
+        ));
+
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM events WHERE claim_id = ?1", params![claim_id])
+                .unwrap();
+            for event in remaining {
+                self.insert(tx, event);
+            }
+            self.enforce_limits(tx);
+        });
+    }
+}
+
+// Unix timestamp of when the event was created, so events that are
+// put back after a failed dispatch keep their place in line
+fn created_at(event: &Event) -> i64 {
+    UTC.datetime_from_str(&event.datetime, "%Y-%m-%d %H:%M:%S%.f UTC")
+        .map(|datetime| datetime.timestamp())
+        .unwrap_or_else(|_| UTC::now().timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{DEFAULT_MAX_LOG_BYTES, DEFAULT_MAX_LOG_EVENTS};
+    use crate::testing::{test_event, test_stored_event, TempDir};
+    use std::thread;
+
+    fn default_limits() -> LogLimits {
+        LogLimits {
+            max_bytes: DEFAULT_MAX_LOG_BYTES,
+            max_events: DEFAULT_MAX_LOG_EVENTS,
+        }
+    }
+
+    #[test]
+    fn created_at_from_datetime() {
+        let mut event = test_event("test");
+        event.datetime = "2021-06-01 12:00:00.123456789 UTC".to_string();
+        assert_eq!(created_at(&event), 1622548800);
+    }
+
+    #[test]
+    fn claim_events() {
+        let dir = TempDir::new();
+        let store = SqliteStore::new(dir.path(), &default_limits());
+        store.record_event(&test_stored_event("first"));
+        store.record_event(&test_stored_event("second"));
+
+        let (claim, events) = store.claim_events().unwrap();
+        assert_eq!(events.len(), 2);
+
+        // Recorded mid-dispatch, and not part of the first claim
+        store.record_event(&test_stored_event("third"));
+        let (other_claim, other_events) = store.claim_events().unwrap();
+        assert_eq!(other_events.len(), 1);
+        store.release_claim(other_claim, &[]);
+
+        let mut failed = events[1].clone();
+        failed.attempts = 1;
+        store.release_claim(claim, &[failed]);
+
+        let (claim, events) = store.claim_events().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.slug, "second");
+        assert_eq!(events[0].attempts, 1);
+        store.release_claim(claim, &[]);
+
+        // Dead letters are kept apart from the events waiting to be sent
+        SqliteStore::dead_letter(dir.path(), &default_limits())
+            .record_event(&test_stored_event("dead"));
+        let (claim, events) = store.claim_events().unwrap();
+        assert!(events.is_empty());
+        store.release_claim(claim, &[]);
+    }
+
+    #[test]
+    fn remove_event() {
+        let dir = TempDir::new();
+        let store = SqliteStore::new(dir.path(), &default_limits());
+        let sent = test_stored_event("sent");
+        store.record_event(&sent);
+        store.record_event(&test_stored_event("unsent"));
+        assert!(store.remove_event(&sent.event.id));
+
+        let (claim, events) = store.claim_events().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.slug, "unsent");
+
+        // Claimed events are left for the dispatch
+        assert!(!store.remove_event(&events[0].event.id));
+        store.release_claim(claim, &events);
+        let (claim, events) = store.claim_events().unwrap();
+        assert_eq!(events.len(), 1);
+        store.release_claim(claim, &[]);
+    }
+
+    #[test]
+    fn log_limits() {
+        let dir = TempDir::new();
+        let store = SqliteStore::new(
+            dir.path(),
+            &LogLimits {
+                max_bytes: DEFAULT_MAX_LOG_BYTES,
+                max_events: 3,
+            },
+        );
+        for i in 0..5 {
+            store.record_event(&test_stored_event(&i.to_string()));
+        }
+        assert_eq!(store.take_dropped(), 2);
+        assert_eq!(store.take_dropped(), 0);
+
+        let (claim, events) = store.claim_events().unwrap();
+        let slugs: Vec<&str> = events
+            .iter()
+            .map(|event| event.event.slug.as_str())
+            .collect();
+        assert_eq!(slugs, vec!["2", "3", "4"]);
+        store.release_claim(claim, &[]);
+    }
+
+    #[test]
+    fn quarantine_corrupt_rows() {
+        let dir = TempDir::new();
+        let store = SqliteStore::new(dir.path(), &default_limits());
+        store.record_event(&test_stored_event("test"));
+        store.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO events (status, created_at, type, data)
+                VALUES ('pending', 0, 'test', '{\"slug\": ')",
+                [],
+            )
+            .unwrap();
+        });
+
+        let (claim, events) = store.claim_events().unwrap();
+        assert_eq!(events.len(), 1);
+        store.release_claim(claim, &[]);
+
+        let corrupt: i64 = store.transaction(|tx| {
+            tx.query_row(
+                "SELECT COUNT(*) FROM events WHERE status = 'corrupt'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        });
+        assert_eq!(corrupt, 1);
+    }
+
+    #[test]
+    fn concurrent_writers() {
+        let dir = TempDir::new();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let store = SqliteStore::new(dir.path(), &default_limits());
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        store.record_event(&test_stored_event("test"));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let store = SqliteStore::new(dir.path(), &default_limits());
+        let (claim, events) = store.claim_events().unwrap();
+        assert_eq!(events.len(), 100);
+        store.release_claim(claim, &[]);
+    }
+}
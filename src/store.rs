@@ -0,0 +1,85 @@
+use crate::events::{EventLog, LogLimits, StoredEvent};
+use std::fs;
+use std::path;
+
+// Where events wait to be sent. The default is the flat events.log (EventLog),
+// with an optional SQLite database behind the "sqlite" cargo feature.
+pub trait EventStore: Send {
+    // Once the store is over its size or event limit, the oldest
+    // events are dropped and counted, instead of growing forever
+    fn record_event(&self, event: &StoredEvent);
+
+    // Removes an event once the sender thread has delivered it. Events
+    // already claimed by a dispatch are left alone, the API skips the
+    // second copy by its id. Returns whether it was removed.
+    fn remove_event(&self, id: &str) -> bool;
+
+    // Returns and resets the number of events dropped to stay within the limits
+    fn take_dropped(&self) -> u64;
+
+    // Takes the stored events for sending. Returns None if they
+    // can't be claimed because another process is dispatching them.
+    // Events that no longer parse are kept aside with the reason,
+    // instead of being silently lost.
+    fn claim_events(&self) -> Option<(Claim, Vec<StoredEvent>)>;
+
+    // Finishes a dispatch, keeping only the events that still need to be sent
+    fn release_claim(&self, claim: Claim, remaining: &[StoredEvent]);
+}
+
+// Held for the length of a dispatch
+pub enum Claim {
+    // Lock that keeps other processes from dispatching the same log
+    File {
+        _lock: fs::File,
+    },
+    // Id of the claimed rows
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
+}
+
+// Parsed from a CLS_EVENT_STORE style spec, "file" (default) or "sqlite"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreKind {
+    File,
+    Sqlite,
+}
+
+impl StoreKind {
+    pub fn parse(spec: &str) -> Result<StoreKind, String> {
+        match spec.trim() {
+            "" | "file" => Ok(StoreKind::File),
+            "sqlite" => Ok(StoreKind::Sqlite),
+            spec => Err(format!("unknown event store {:?}", spec)),
+        }
+    }
+}
+
+pub fn open(kind: StoreKind, dir: &path::Path, limits: &LogLimits) -> Box<dyn EventStore> {
+    match kind {
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => Box::new(crate::sqlite::SqliteStore::new(dir, limits)),
+        #[cfg(not(feature = "sqlite"))]
+        StoreKind::Sqlite => {
+            super::debug_print(
+                "cls was built without the sqlite feature, using events.log".to_string(),
+            );
+            Box::new(EventLog::new(dir, limits))
+        }
+        StoreKind::File => Box::new(EventLog::new(dir, limits)),
+    }
+}
+
+// Where events end up when they can't be sent, after running out
+// of retry attempts or being rejected by the API
+pub fn open_dead_letter(
+    kind: StoreKind,
+    dir: &path::Path,
+    limits: &LogLimits,
+) -> Box<dyn EventStore> {
+    match kind {
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => Box::new(crate::sqlite::SqliteStore::dead_letter(dir, limits)),
+        _ => Box::new(EventLog::dead_letter(dir, limits)),
+    }
+}
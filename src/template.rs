@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Text(String),
+    Var(String),
+    Open(String, bool),
+    Close(String),
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Var(String),
+    Section {
+        name: String,
+        inverted: bool,
+        children: Vec<Node>,
+    },
+}
+
+// Renders one of the prompts, which can use:
+//
+//   {name}                 replaced with the value of name
+//   {#name}...{/name}      only shown if name isn't empty
+//   {^name}...{/name}      only shown if name is empty
+//   {{ and }}              a literal { and }, like {{name}} for "{name}"
+//
+// Anything else in braces that isn't a name (like a JSON example) is left as
+// it is, and a }} is only unescaped when it closes a {{, so nested JSON like
+// {"a": {"b": 1}} comes out unchanged. Also returns any problems with the
+// template, like unknown placeholders, which are left in the output as written.
+pub fn render(template: &str, values: &HashMap<&str, String>) -> (String, Vec<String>) {
+    let mut errors = Vec::new();
+    let nodes = parse(tokenize(template), &mut errors);
+    let mut out = String::new();
+    render_nodes(&nodes, values, &mut out, &mut errors);
+    (out, errors)
+}
+
+fn tokenize(template: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+    // {{ that haven't been closed by a }} yet
+    let mut escaped = 0;
+
+    while let Some(c) = rest.chars().next() {
+        let unescape = if rest.starts_with("{{") {
+            escaped += 1;
+            true
+        } else if rest.starts_with("}}") && escaped > 0 {
+            escaped -= 1;
+            true
+        } else {
+            false
+        };
+        if unescape {
+            text.push(c);
+            rest = &rest[2..];
+            continue;
+        }
+        if c == '{' {
+            if let Some((tag, len)) = parse_tag(rest) {
+                if !text.is_empty() {
+                    tokens.push(Token::Text(std::mem::take(&mut text)));
+                }
+                tokens.push(tag);
+                rest = &rest[len..];
+                continue;
+            }
+        }
+        text.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    tokens
+}
+
+// A tag at the start of s, and how long it is
+fn parse_tag(s: &str) -> Option<(Token, usize)> {
+    let end = s.find('}')?;
+    let inner = &s[1..end];
+    let (sigil, name) = match inner.chars().next()? {
+        sigil @ ('#' | '^' | '/') => (Some(sigil), &inner[1..]),
+        _ => (None, inner),
+    };
+
+    let is_name = matches!(name.chars().next(), Some(c) if c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !is_name {
+        return None;
+    }
+
+    let name = name.to_string();
+    let token = match sigil {
+        Some('#') => Token::Open(name, false),
+        Some('^') => Token::Open(name, true),
+        Some(_) => Token::Close(name),
+        None => Token::Var(name),
+    };
+    Some((token, end + 1))
+}
+
+// A section being filled in while parsing, the top level has no name
+type Frame = (Option<(String, bool)>, Vec<Node>);
+
+fn parse(tokens: Vec<Token>, errors: &mut Vec<String>) -> Vec<Node> {
+    // Innermost last
+    let mut stack: Vec<Frame> = vec![(None, Vec::new())];
+
+    for token in tokens {
+        match token {
+            Token::Text(text) => stack.last_mut().unwrap().1.push(Node::Text(text)),
+            Token::Var(name) => stack.last_mut().unwrap().1.push(Node::Var(name)),
+            Token::Open(name, inverted) => stack.push((Some((name, inverted)), Vec::new())),
+            Token::Close(name) => {
+                let open = stack.last().unwrap().0.as_ref().map(|(open, _)| open);
+                if open != Some(&name) {
+                    errors.push(format!("unexpected {{/{}}}", name));
+                    let text = format!("{{/{}}}", name);
+                    stack.last_mut().unwrap().1.push(Node::Text(text));
+                    continue;
+                }
+                close_section(&mut stack);
+            }
+        }
+    }
+
+    while stack.len() > 1 {
+        let name = &stack.last().unwrap().0.as_ref().unwrap().0;
+        errors.push(format!("unclosed section {{#{}}}", name));
+        close_section(&mut stack);
+    }
+    stack.pop().unwrap().1
+}
+
+fn close_section(stack: &mut Vec<Frame>) {
+    let (section, children) = stack.pop().unwrap();
+    let (name, inverted) = section.unwrap();
+    stack.last_mut().unwrap().1.push(Node::Section {
+        name,
+        inverted,
+        children,
+    });
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    values: &HashMap<&str, String>,
+    out: &mut String,
+    errors: &mut Vec<String>,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(name) => match values.get(name.as_str()) {
+                Some(value) => out.push_str(value),
+                None => {
+                    errors.push(format!("unknown placeholder {{{}}}", name));
+                    out.push_str(&format!("{{{}}}", name));
+                }
+            },
+            Node::Section {
+                name,
+                inverted,
+                children,
+            } => {
+                let present = match values.get(name.as_str()) {
+                    Some(value) => !value.is_empty(),
+                    None => {
+                        errors.push(format!("unknown placeholder {{#{}}}", name));
+                        false
+                    }
+                };
+                if present != *inverted {
+                    render_nodes(children, values, out, errors);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> HashMap<&'static str, String> {
+        let mut values = HashMap::new();
+        values.insert("project_slug", "mytool".to_string());
+        values.insert("opt_out_command", "mytool telemetry off".to_string());
+        values.insert("privacy_policy_url", "".to_string());
+        values
+    }
+
+    #[test]
+    fn placeholders() {
+        let (out, errors) = render("Help improve {project_slug}!", &values());
+        assert_eq!(out, "Help improve mytool!");
+        assert!(errors.is_empty());
+
+        // Values aren't templates themselves
+        let mut values = values();
+        values.insert("event_data", "{\"slug\": \"{project_slug}\"}".to_string());
+        let (out, _) = render("{event_data}", &values);
+        assert_eq!(out, "{\"slug\": \"{project_slug}\"}");
+    }
+
+    #[test]
+    fn sections() {
+        let template =
+            "Data{#opt_out_command}, run `{opt_out_command}` to opt out{/opt_out_command}.\
+            {#privacy_policy_url} See {privacy_policy_url}{/privacy_policy_url}\
+            {^privacy_policy_url} No policy.{/privacy_policy_url}";
+        let (out, errors) = render(template, &values());
+        assert_eq!(
+            out,
+            "Data, run `mytool telemetry off` to opt out. No policy."
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn escaping() {
+        let (out, errors) = render("{{project_slug}} is {project_slug}, {\"a\": 1}", &values());
+        assert_eq!(out, "{project_slug} is mytool, {\"a\": 1}");
+        assert!(errors.is_empty());
+
+        let json = "{\"a\": {\"b\": 1}}";
+        assert_eq!(render(json, &values()).0, json);
+        let (out, _) = render("{{\"a\": {{\"b\": {project_slug}}}}}", &values());
+        assert_eq!(out, "{\"a\": {\"b\": mytool}}");
+    }
+
+    #[test]
+    fn errors() {
+        let (out, errors) = render("{project} {/project_slug}{#version}x", &values());
+        assert_eq!(out, "{project} {/project_slug}");
+        assert_eq!(
+            errors,
+            vec![
+                "unexpected {/project_slug}",
+                "unclosed section {#version}",
+                "unknown placeholder {project}",
+                "unknown placeholder {#version}",
+            ]
+        );
+    }
+}
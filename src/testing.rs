@@ -0,0 +1,52 @@
+// Fixtures shared by the unit tests
+use crate::events::{Event, StoredEvent};
+use crate::truncate::MetadataLimits;
+use std::fs;
+use std::path;
+use uuid::Uuid;
+
+pub fn test_event(slug: &str) -> Event {
+    Event::new(
+        slug,
+        "test",
+        serde_json::Value::Null,
+        "test",
+        "test",
+        &false,
+        "1.0.0",
+        &MetadataLimits::default(),
+    )
+}
+
+// As it would be read back from an EventStore, with no failed attempts
+pub fn test_stored_event(slug: &str) -> StoredEvent {
+    StoredEvent::new(test_event(slug))
+}
+
+// A fresh directory under the system temp dir, removed when
+// dropped so it's cleaned up even if the test panics
+pub struct TempDir {
+    path: path::PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let path = std::env::temp_dir().join(format!("cls-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    pub fn join(&self, name: &str) -> path::PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::api::{APIClient, ApiError, ClientOptions};
+use crate::events::Event;
+use fs2::FileExt;
+use std::fs;
+use std::io::{self, Write};
+use std::iter;
+use std::path;
+
+// The outcome of each chunk of events a transport sent, produced as the
+// iterator is consumed so the caller can stop early
+pub type SendResults<'a> = Box<dyn Iterator<Item = (&'a [Event], Result<(), ApiError>)> + 'a>;
+
+pub trait Transport: Send {
+    fn send<'a>(&'a self, events: &'a [Event]) -> SendResults<'a>;
+}
+
+// Which transport to use, parsed from a CLS_TRANSPORT style spec:
+// "http" (default), "file:<path>", "stdout", "stderr" or "null"
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportConfig {
+    Http(ClientOptions),
+    File(path::PathBuf),
+    Stdout,
+    Stderr,
+    Null,
+}
+
+impl TransportConfig {
+    pub fn parse(spec: &str, client_options: ClientOptions) -> Result<TransportConfig, ApiError> {
+        match spec.trim() {
+            "" | "http" => Ok(TransportConfig::Http(client_options)),
+            "stdout" => Ok(TransportConfig::Stdout),
+            "stderr" => Ok(TransportConfig::Stderr),
+            "null" => Ok(TransportConfig::Null),
+            spec => match spec.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(TransportConfig::File(path.into())),
+                _ => Err(ApiError::Config(format!("unknown transport {:?}", spec))),
+            },
+        }
+    }
+
+    pub fn build(&self) -> Result<Box<dyn Transport>, ApiError> {
+        Ok(match self {
+            TransportConfig::Http(options) => Box::new(HttpTransport {
+                api: APIClient::new(options)?,
+            }),
+            TransportConfig::File(path) => Box::new(FileTransport { path: path.clone() }),
+            TransportConfig::Stdout => Box::new(StreamTransport { stderr: false }),
+            TransportConfig::Stderr => Box::new(StreamTransport { stderr: true }),
+            TransportConfig::Null => Box::new(NullTransport),
+        })
+    }
+}
+
+// The cls API, in batches
+pub struct HttpTransport {
+    api: APIClient,
+}
+
+impl Transport for HttpTransport {
+    fn send<'a>(&'a self, events: &'a [Event]) -> SendResults<'a> {
+        Box::new(self.api.post_events(events))
+    }
+}
+
+// Appends events to a file as NDJSON, for air-gapped machines
+// or to collect events from a test run
+pub struct FileTransport {
+    path: path::PathBuf,
+}
+
+impl FileTransport {
+    fn append(&self, events: &[Event]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        // Other processes may be writing to the same file
+        file.lock_exclusive()?;
+
+        let mut lines = String::new();
+        for event in events {
+            lines += &serde_json::to_string(event).unwrap();
+            lines += "\n";
+        }
+        file.write_all(lines.as_bytes())
+    }
+}
+
+impl Transport for FileTransport {
+    fn send<'a>(&'a self, events: &'a [Event]) -> SendResults<'a> {
+        let result = self.append(events).map_err(ApiError::Io);
+        Box::new(iter::once((events, result)))
+    }
+}
+
+// Prints events as NDJSON, to see exactly what would be sent
+pub struct StreamTransport {
+    stderr: bool,
+}
+
+impl Transport for StreamTransport {
+    fn send<'a>(&'a self, events: &'a [Event]) -> SendResults<'a> {
+        let mut lines = String::new();
+        for event in events {
+            lines += &serde_json::to_string(event).unwrap();
+            lines += "\n";
+        }
+        let result = if self.stderr {
+            io::stderr().write_all(lines.as_bytes())
+        } else {
+            io::stdout().write_all(lines.as_bytes())
+        };
+        Box::new(iter::once((events, result.map_err(ApiError::Io))))
+    }
+}
+
+// Accepts and discards everything
+pub struct NullTransport;
+
+impl Transport for NullTransport {
+    fn send<'a>(&'a self, events: &'a [Event]) -> SendResults<'a> {
+        Box::new(iter::once((events, Ok(()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_event, TempDir};
+
+    fn client_options() -> ClientOptions {
+        ClientOptions {
+            token: "test".to_string(),
+            gzip: true,
+            timeout: std::time::Duration::from_secs(10),
+            proxy: None,
+            ca_bundle: None,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            TransportConfig::parse("", client_options()).unwrap(),
+            TransportConfig::Http(client_options())
+        );
+        assert_eq!(
+            TransportConfig::parse("file:/tmp/events.ndjson", client_options()).unwrap(),
+            TransportConfig::File("/tmp/events.ndjson".into())
+        );
+        assert_eq!(
+            TransportConfig::parse("stderr", client_options()).unwrap(),
+            TransportConfig::Stderr
+        );
+        assert!(TransportConfig::parse("file:", client_options()).is_err());
+        assert!(TransportConfig::parse("carrier-pigeon", client_options()).is_err());
+    }
+
+    #[test]
+    fn file_transport() {
+        let dir = TempDir::new();
+        let path = dir.join("events.ndjson");
+        let transport = TransportConfig::File(path.clone()).build().unwrap();
+        let events = vec![test_event("test"); 3];
+
+        let results: Vec<_> = transport.send(&events).collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_ok());
+        transport.send(&events[..1]).for_each(drop);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 4);
+    }
+}
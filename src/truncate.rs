@@ -0,0 +1,205 @@
+use serde_json::Value;
+
+pub const DEFAULT_MAX_METADATA_BYTES: usize = 64 * 1024;
+pub const DEFAULT_MAX_STRING_BYTES: usize = 16 * 1024;
+pub const DEFAULT_MAX_ARRAY_ITEMS: usize = 100;
+pub const DEFAULT_MAX_METADATA_DEPTH: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataLimits {
+    // Of the whole metadata, serialized
+    pub max_bytes: usize,
+    pub max_string_bytes: usize,
+    pub max_array_items: usize,
+    // How many levels of objects and arrays
+    pub max_depth: usize,
+}
+
+impl Default for MetadataLimits {
+    fn default() -> MetadataLimits {
+        MetadataLimits {
+            max_bytes: DEFAULT_MAX_METADATA_BYTES,
+            max_string_bytes: DEFAULT_MAX_STRING_BYTES,
+            max_array_items: DEFAULT_MAX_ARRAY_ITEMS,
+            max_depth: DEFAULT_MAX_METADATA_DEPTH,
+        }
+    }
+}
+
+fn marker(bytes: usize) -> String {
+    format!("…[truncated {} bytes]", bytes)
+}
+
+fn serialized_len(value: &Value) -> usize {
+    serde_json::to_string(value).unwrap().len()
+}
+
+// Cuts the metadata down to the limits, replacing what was removed with a
+// marker saying how much, and returns the paths of the values that were cut
+// (like "metadata.output" or "metadata.frames[3]").
+//
+// If it's still over max_bytes after the string, array and depth limits, the
+// strings are cut shorter and shorter until it fits, so the same metadata
+// always comes out the same way.
+pub fn truncate(metadata: Value, limits: &MetadataLimits) -> (Value, Vec<String>) {
+    let mut max_string_bytes = limits.max_string_bytes;
+    loop {
+        let mut value = metadata.clone();
+        let mut truncated = Vec::new();
+        truncate_value(
+            &mut value,
+            limits,
+            max_string_bytes,
+            "metadata",
+            0,
+            &mut truncated,
+        );
+        if serialized_len(&value) <= limits.max_bytes {
+            return (value, truncated);
+        }
+        if max_string_bytes == 0 {
+            // Too many values to fit even with every string emptied
+            let bytes = serialized_len(&metadata);
+            return (Value::String(marker(bytes)), vec!["metadata".to_string()]);
+        }
+        max_string_bytes /= 2;
+    }
+}
+
+fn truncate_value(
+    value: &mut Value,
+    limits: &MetadataLimits,
+    max_string_bytes: usize,
+    path: &str,
+    depth: usize,
+    truncated: &mut Vec<String>,
+) {
+    match value {
+        Value::String(s) if s.len() > max_string_bytes => {
+            let mut end = max_string_bytes;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            let removed = s.len() - end;
+            s.truncate(end);
+            s.push_str(&marker(removed));
+            truncated.push(path.to_string());
+        }
+        Value::Array(_) | Value::Object(_) if depth >= limits.max_depth => {
+            *value = Value::String(marker(serialized_len(value)));
+            truncated.push(path.to_string());
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                let item_path = format!("{}[{}]", path, i);
+                truncate_value(
+                    item,
+                    limits,
+                    max_string_bytes,
+                    &item_path,
+                    depth + 1,
+                    truncated,
+                );
+            }
+            if items.len() > limits.max_array_items {
+                let removed: usize = items[limits.max_array_items..]
+                    .iter()
+                    .map(|item| serialized_len(item) + 1)
+                    .sum();
+                items.truncate(limits.max_array_items);
+                items.push(Value::String(marker(removed)));
+                truncated.push(path.to_string());
+            }
+        }
+        Value::Object(object) => {
+            for (key, item) in object.iter_mut() {
+                let item_path = format!("{}.{}", path, key);
+                truncate_value(
+                    item,
+                    limits,
+                    max_string_bytes,
+                    &item_path,
+                    depth + 1,
+                    truncated,
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn limits() -> MetadataLimits {
+        MetadataLimits {
+            max_bytes: 1000,
+            max_string_bytes: 10,
+            max_array_items: 3,
+            max_depth: 2,
+        }
+    }
+
+    #[test]
+    fn within_limits() {
+        let metadata = json!({"command": "build", "args": ["-v"], "ok": true});
+        let (value, truncated) = truncate(metadata.clone(), &limits());
+        assert_eq!(value, metadata);
+        assert!(truncated.is_empty());
+    }
+
+    #[test]
+    fn truncate_strings_arrays_and_depth() {
+        let metadata = json!({
+            "output": "0123456789abcdef",
+            "emoji": "ééééééé",
+            "args": [1, 2, 3, 4, 5],
+            "nested": {"a": {"b": 1}, "c": 1},
+        });
+        let (value, truncated) = truncate(metadata, &limits());
+        assert_eq!(
+            value,
+            json!({
+                "output": "0123456789…[truncated 6 bytes]",
+                "emoji": "ééééé…[truncated 4 bytes]",
+                "args": [1, 2, 3, "…[truncated 4 bytes]"],
+                "nested": {"a": "…[truncated 7 bytes]", "c": 1},
+            })
+        );
+        assert_eq!(
+            truncated,
+            vec![
+                "metadata.args",
+                "metadata.emoji",
+                "metadata.nested.a",
+                "metadata.output"
+            ]
+        );
+    }
+
+    #[test]
+    fn truncate_total_bytes() {
+        let limits = MetadataLimits {
+            max_bytes: 200,
+            max_string_bytes: 1000,
+            ..limits()
+        };
+        let metadata = json!({"stdout": "x".repeat(500), "stderr": "y".repeat(50)});
+        let (value, truncated) = truncate(metadata.clone(), &limits);
+        assert!(serialized_len(&value) <= 200);
+        assert_eq!(truncated, vec!["metadata.stdout"]);
+        assert_eq!(value["stderr"], metadata["stderr"]);
+        // Deterministic
+        assert_eq!(truncate(metadata, &limits).0, value);
+
+        let limits = MetadataLimits {
+            max_bytes: 5,
+            ..limits
+        };
+        let (value, truncated) = truncate(json!([1, 2, 3]), &limits);
+        assert_eq!(value, json!("…[truncated 7 bytes]"));
+        assert_eq!(truncated, vec!["metadata"]);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::api::ApiError;
+use crate::consent::Category;
+use crate::events::{Event, StoredEvent};
+use crate::retry::{Outcome, RetryPolicy};
+use crate::settings;
+use crate::store::EventStore;
+use crate::transport::{Transport, TransportConfig};
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many events can be waiting on the sender thread before track_event
+// stops queueing them, and leaves them in the EventStore for dispatch_events
+const QUEUE_CAPACITY: usize = 100;
+
+// How long to stop sending after the API says the project key is bad, or
+// the API URL is wrong, which in practice is the rest of the host command
+const MISCONFIGURED_PAUSE: Duration = Duration::from_secs(60 * 60);
+
+// Everything the sender thread needs to deliver an event, captured on the
+// caller's thread so the worker never touches SETTINGS (debug_print and
+// settings::global_opt_out don't either). The event is already
+// in the log, so it survives the process exiting before it's sent, and is
+// only removed from there once it has been.
+pub struct Job {
+    pub event: StoredEvent,
+    pub category: Category,
+    pub transport: TransportConfig,
+    pub retry_policy: RetryPolicy,
+    pub log: Box<dyn EventStore>,
+    pub dead_letter: Box<dyn EventStore>,
+}
+
+// The transport built from a config, or why it couldn't be
+type CachedTransport = (TransportConfig, Result<Box<dyn Transport>, String>);
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    in_flight: usize,
+    started: bool,
+}
+
+struct Channel {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    idle: Condvar,
+}
+
+static CHANNEL: Lazy<Channel> = Lazy::new(|| Channel {
+    queue: Mutex::new(Queue {
+        jobs: VecDeque::new(),
+        in_flight: 0,
+        started: false,
+    }),
+    ready: Condvar::new(),
+    idle: Condvar::new(),
+});
+
+// Queues a job for the background sender, starting it if needed.
+// Hands the job back if the queue is full or the thread can't be started.
+pub fn send(job: Job) -> Result<(), Box<Job>> {
+    let mut queue = CHANNEL.queue.lock().unwrap();
+
+    if !queue.started {
+        let spawned = thread::Builder::new()
+            .name("cls-sender".to_string())
+            .spawn(run);
+        if spawned.is_err() {
+            return Err(Box::new(job));
+        }
+        queue.started = true;
+    }
+
+    if queue.jobs.len() >= QUEUE_CAPACITY {
+        return Err(Box::new(job));
+    }
+
+    queue.jobs.push_back(job);
+    CHANNEL.ready.notify_one();
+    Ok(())
+}
+
+// Waits for queued events to be sent. Anything still queued when the timeout
+// runs out is left in the EventStore for a later dispatch_events.
+// Returns false if the timeout was hit.
+pub fn flush(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut queue = CHANNEL.queue.lock().unwrap();
+
+    while !queue.jobs.is_empty() || queue.in_flight > 0 {
+        let now = Instant::now();
+        if now >= deadline {
+            let unsent = queue.jobs.drain(..).count();
+            drop(queue);
+
+            super::debug_print(format!(
+                "flush timed out, leaving {:?} queued events in the event log",
+                unsent
+            ));
+            return false;
+        }
+        queue = CHANNEL.idle.wait_timeout(queue, deadline - now).unwrap().0;
+    }
+
+    true
+}
+
+// Drops queued jobs for categories the user has since turned off. Their
+// events are still in the EventStore, for the caller to clear out.
+pub fn discard(categories: &[Category]) {
+    let mut queue = CHANNEL.queue.lock().unwrap();
+    let queued = queue.jobs.len();
+    queue.jobs.retain(|job| !categories.contains(&job.category));
+    let discarded = queued - queue.jobs.len();
+    if queue.jobs.is_empty() && queue.in_flight == 0 {
+        CHANNEL.idle.notify_all();
+    }
+    drop(queue);
+
+    if discarded > 0 {
+        super::debug_print(format!("discarded {:?} queued events", discarded));
+    }
+}
+
+fn run() {
+    // Reuse the transport between events, unless the settings change
+    let mut transport: Option<CachedTransport> = None;
+    // Set when the API asks us to back off, jobs go straight to the EventLog until then
+    let mut paused_until: Option<Instant> = None;
+
+    loop {
+        let job = {
+            let mut queue = CHANNEL.queue.lock().unwrap();
+            while queue.jobs.is_empty() {
+                queue = CHANNEL.ready.wait(queue).unwrap();
+            }
+            queue.in_flight += 1;
+            queue.jobs.pop_front().unwrap()
+        };
+
+        // A bug in sending one event shouldn't stop the thread, or leave
+        // flush waiting on an event that will never finish
+        let sent = panic::catch_unwind(AssertUnwindSafe(|| {
+            send_job(job, &mut transport, &mut paused_until)
+        }));
+        if sent.is_err() {
+            super::debug_print("sender panicked, the event was left in the event log".to_string());
+        }
+
+        let mut queue = CHANNEL.queue.lock().unwrap();
+        queue.in_flight -= 1;
+        if queue.jobs.is_empty() && queue.in_flight == 0 {
+            CHANNEL.idle.notify_all();
+        }
+    }
+}
+
+fn send_job(job: Job, transport: &mut Option<CachedTransport>, paused_until: &mut Option<Instant>) {
+    // The user may have opted out since the event was queued
+    if let Some(reason) = settings::global_opt_out() {
+        super::debug_print(format!("sender dropping event, {}", reason));
+        job.log.remove_event(&job.event.event.id);
+        return;
+    }
+
+    let stale = match &transport {
+        Some((config, _)) => config != &job.transport,
+        None => true,
+    };
+    if stale {
+        let built = job.transport.build().map_err(|err| match err {
+            ApiError::Config(message) => message,
+            err => err.to_string(),
+        });
+        *transport = Some((job.transport.clone(), built));
+        *paused_until = None;
+    }
+
+    let result = if paused_until.is_some_and(|until| Instant::now() < until) {
+        None
+    } else {
+        match &transport.as_ref().unwrap().1 {
+            Ok(transport) => Some(send_event(transport.as_ref(), &job.event.event)),
+            Err(message) => Some(Err(ApiError::Config(message.clone()))),
+        }
+    };
+
+    match result {
+        Some(Ok(_)) => {
+            job.log.remove_event(&job.event.event.id);
+        }
+        // Leave it for a later dispatch_events, without counting an attempt
+        None => {}
+        Some(Err(err)) => {
+            super::debug_print(format!("sender failed to send event: {}", err));
+
+            if err.should_pause() {
+                let pause = match err.retry_after() {
+                    Some(seconds) => Duration::from_secs(seconds),
+                    None if err.is_retryable() => Duration::from_secs(job.retry_policy.base_delay),
+                    None => MISCONFIGURED_PAUSE,
+                };
+                *paused_until = Some(Instant::now() + pause);
+            }
+
+            // Saved again with the failed attempt counted. If it's no longer
+            // in the log, a dispatch has claimed it and will retry it itself.
+            if !job.log.remove_event(&job.event.event.id) {
+                return;
+            }
+            match job.retry_policy.handle_error(job.event, &err) {
+                Outcome::Requeue(event) => job.log.record_event(&event),
+                Outcome::DeadLetter(event) => job.dead_letter.record_event(&event),
+            }
+        }
+    }
+}
+
+fn send_event(transport: &dyn Transport, event: &Event) -> Result<(), ApiError> {
+    let mut results = transport.send(std::slice::from_ref(event));
+    match results.next() {
+        Some((_, result)) => result,
+        None => Ok(()),
+    }
+}